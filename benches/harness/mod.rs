@@ -0,0 +1,78 @@
+//! A fixed suite of Forth programs, timed by instructions executed per
+//! second so runs on different versions of the VM can be compared.
+
+use std::time::{Duration, Instant};
+
+use tiny_forth::{Parser, VM};
+
+/// One benchmark: its name, its source and the stack it must finish with.
+pub struct Benchmark {
+    pub name: &'static str,
+    pub source: &'static str,
+    pub expected: &'static [i32],
+}
+
+pub const SUITE: &[Benchmark] = &[
+    Benchmark {
+        name: "arithmetic-loop",
+        // Sums 1..=100000, wrapping
+        source: "0 100000 begin swap over + swap 1 - dup 0 = until drop",
+        expected: &[705082704],
+    },
+    Benchmark {
+        name: "recursion",
+        source: ": down dup if 1 - down then ; 300 begin 200 down drop 1 - dup 0 = until drop",
+        expected: &[],
+    },
+    Benchmark {
+        name: "stack-shuffling",
+        // Each pass leaves `a b n` as it was, apart from counting n down
+        source: "1 2 50000 begin swap swap over drop tuck drop swap dup drop 1 - dup 0 = until drop drop drop",
+        expected: &[],
+    },
+];
+
+/// Timings for one benchmark, summed over every iteration.
+pub struct BenchResult {
+    pub name: &'static str,
+    pub steps: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub fn steps_per_sec(&self) -> f64 {
+        self.steps as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Runs every benchmark in `SUITE` `iterations` times. Parsing is not
+/// timed. Panics if a benchmark fails or finishes with the wrong stack,
+/// since its timing would mean nothing.
+pub fn run_suite(iterations: usize) -> Vec<BenchResult> {
+    SUITE.iter().map(|bench| {
+        let mut result = BenchResult { name: bench.name, steps: 0, elapsed: Duration::ZERO };
+        for _ in 0..iterations {
+            let mut parser = Parser::new();
+            parser.parse(bench.source).expect("benchmark should parse");
+            let mut vm = VM::from_parser(parser).with_output(std::io::sink());
+            let start = Instant::now();
+            vm.run().unwrap_or_else(|err| panic!("{}: {}", bench.name, err));
+            result.elapsed += start.elapsed();
+            assert_eq!(vm.stack(), bench.expected, "{} left the wrong stack", bench.name);
+            result.steps += vm.steps();
+        }
+        result
+    }).collect()
+}
+
+/// The results as a JSON array, one object per benchmark, for comparing
+/// runs.
+pub fn to_json(results: &[BenchResult]) -> String {
+    let entries: Vec<String> = results.iter()
+        .map(|result| format!(
+            "  {{\"name\": \"{}\", \"steps\": {}, \"seconds\": {:.6}, \"steps_per_sec\": {:.0}}}",
+            result.name, result.steps, result.elapsed.as_secs_f64(), result.steps_per_sec(),
+        ))
+        .collect();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
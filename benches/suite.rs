@@ -0,0 +1,23 @@
+//! `cargo bench` prints instructions per second for each program in the
+//! suite. `cargo bench --bench suite -- --json <file>` also writes the
+//! results as JSON.
+
+mod harness;
+
+const ITERATIONS: usize = 20;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let json = args.iter().position(|arg| arg == "--json").and_then(|i| args.get(i + 1));
+
+    let results = harness::run_suite(ITERATIONS);
+    for result in &results {
+        println!("{:<16} {:>12} steps {:>14.0} steps/s", result.name, result.steps, result.steps_per_sec());
+    }
+    if let Some(path) = json
+        && let Err(err) = std::fs::write(path, harness::to_json(&results))
+    {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,2837 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Push(i32),
+    /// Pushes several literals in order; produced by coalescing runs of `Push`.
+    PushN(Vec<i32>),
+    /// Pushes the address of a `variable`'s cell. Runs like `Push`, but the
+    /// value is tagged as an address in tagged-cell mode.
+    Address(i32),
+    // Arithmetic wraps on overflow rather than trapping, as in most Forths
+    Add,
+    Sub,
+    Mul,
+    /// Truncating division, as Rust's `/`.
+    Div,
+    /// Remainder with the sign of the dividend, as Rust's `%`.
+    Mod,
+    // Comparisons push a Forth flag: -1 (all bits set) for true, 0 for false
+    Eq,
+    Lt,
+    Gt,
+    /// Bitwise, so it is also the logical AND of two flags.
+    And,
+    /// Bitwise, so it is also the logical OR of two flags.
+    Or,
+    /// Logical negation: `0` becomes `-1`, anything else becomes `0`.
+    Not,
+    Dup,
+    Drop,
+    Swap,
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    TwoDup,
+    TwoDrop,
+    TwoSwap,
+    Depth,
+    /// Pushes Forth STATE as it is when this runs: -1 if the parser has a
+    /// definition open, else 0. Code only runs once the parser is done with
+    /// it, so this is 0 unless a REPL line runs while a definition it
+    /// started is still open.
+    State,
+    /// Absolute value, computed without branching. `i32::MIN` has no
+    /// positive counterpart and is left as `i32::MIN`, like `i32::wrapping_abs`.
+    Abs,
+    /// Floored division `( n1 n2 -- rem quot )`; the remainder takes the
+    /// sign of the divisor.
+    FmMod,
+    /// Symmetric division `( n1 n2 -- rem quot )`; the quotient truncates
+    /// toward zero and the remainder takes the sign of the dividend.
+    SmRem,
+    /// Pops `n` and pushes `0 1 ... n-1`.
+    Iota,
+    /// Pops and prints a number followed by a space.
+    Dot,
+    /// Pops a Unicode code point and writes it as UTF-8; surrogates and
+    /// values above 0x10FFFF are an error.
+    Emit,
+    /// Pops an address and pushes the memory cell it names.
+    Fetch,
+    /// `( n addr -- )`: stores `n` in the memory cell at `addr`.
+    Store,
+    /// Marks the start of a `test` block's setup code.
+    TestBegin,
+    /// Marks the end of the setup code and the start of the expected values.
+    TestExpect,
+    /// Compares the setup results with the expected values, records the
+    /// outcome under the test's name and drops both.
+    TestEnd(String),
+    Jump(isize),
+    IfZero(isize),
+    Call(usize),
+    /// Call relative to the current instruction, so the code stays valid
+    /// when it is moved as a whole.
+    RelCall(isize),
+    CallWord(String),
+    Return,
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// `op` needed more items than the stack held, at instruction `ip`.
+    StackUnderflow { op: &'static str, ip: usize },
+    UnknownWord(String),
+    ReturnStackUnderflow,
+    DivideByZero { ip: usize },
+    NegativeCount { op: &'static str, count: i32, ip: usize },
+    /// The run dispatched more instructions than `max_steps` allows.
+    StepLimitExceeded,
+    /// A single call to `word` ran past the per-call step budget.
+    StepBudgetExceeded { word: String },
+    /// `endtest` ran without a matching `test` and `expect`.
+    UnbalancedTest { ip: usize },
+    /// Writing to the output sink failed.
+    Output(std::io::ErrorKind),
+    /// Calling `word` would nest deeper than `return_stack_limit` allows.
+    ReturnStackOverflow { word: String },
+    /// `op` would grow the data stack past `stack_limit`, at instruction `ip`.
+    StackOverflow { op: &'static str, ip: usize },
+    /// `@` or `!` used an address outside the VM's memory.
+    InvalidAddress { addr: i32, ip: usize },
+    /// `emit` was given a surrogate or a value above 0x10FFFF.
+    InvalidChar { value: i32, ip: usize },
+    /// `@` read a cell no `!` had stored to yet, with poison mode on.
+    UninitializedRead { addr: i32, ip: usize },
+    /// `@` or `!` was given a plain number as its address, with tagged cells on.
+    NotAnAddress { value: i32, ip: usize },
+}
+
+impl From<std::io::Error> for VmError {
+    fn from(err: std::io::Error) -> Self {
+        VmError::Output(err.kind())
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { op, ip } => write!(f, "Stack underflow on {} at {}", op, ip),
+            VmError::UnknownWord(name) => write!(f, "Unknown word: {}", name),
+            VmError::ReturnStackUnderflow => write!(f, "Return stack underflow"),
+            VmError::DivideByZero { ip } => write!(f, "Division by zero at {}", ip),
+            VmError::NegativeCount { op, count, ip } => write!(f, "Negative count {} on {} at {}", count, op, ip),
+            VmError::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            VmError::StepBudgetExceeded { word } => write!(f, "Step budget exceeded in word: {}", word),
+            VmError::UnbalancedTest { ip } => write!(f, "endtest without test at {}", ip),
+            VmError::Output(kind) => write!(f, "Output error: {}", kind),
+            VmError::ReturnStackOverflow { word } => write!(f, "Return stack overflow calling word: {}", word),
+            VmError::StackOverflow { op, ip } => write!(f, "Stack overflow on {} at {}", op, ip),
+            VmError::InvalidAddress { addr, ip } => write!(f, "Invalid address {} at {}", addr, ip),
+            VmError::InvalidChar { value, ip } => write!(f, "Invalid character {} at {}", value, ip),
+            VmError::UninitializedRead { addr, ip } => write!(f, "Read of uninitialized address {} at {}", addr, ip),
+            VmError::NotAnAddress { value, ip } => write!(f, "{} is not an address at {}", value, ip),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Documentation for a word the parser compiles directly to an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuiltinInfo {
+    pub name: &'static str,
+    pub effect: &'static str,
+    pub description: &'static str,
+}
+
+pub const BUILTINS: &[BuiltinInfo] = &[
+    BuiltinInfo { name: "dup", effect: "( a -- a a )", description: "Duplicate the top of the stack" },
+    BuiltinInfo { name: "drop", effect: "( a -- )", description: "Discard the top of the stack" },
+    BuiltinInfo { name: "swap", effect: "( a b -- b a )", description: "Exchange the top two items" },
+    BuiltinInfo { name: "over", effect: "( a b -- a b a )", description: "Copy the second item to the top" },
+    BuiltinInfo { name: "tuck", effect: "( a b -- b a b )", description: "Copy the top item below the second" },
+    BuiltinInfo { name: "+", effect: "( a b -- a+b )", description: "Add" },
+    BuiltinInfo { name: "-", effect: "( a b -- a-b )", description: "Subtract" },
+    BuiltinInfo { name: "*", effect: "( a b -- a*b )", description: "Multiply" },
+    BuiltinInfo { name: "/", effect: "( a b -- a/b )", description: "Divide, truncating toward zero" },
+    BuiltinInfo { name: "mod", effect: "( a b -- a%b )", description: "Remainder of /, with the sign of a" },
+    BuiltinInfo { name: "=", effect: "( a b -- flag )", description: "True if a equals b" },
+    BuiltinInfo { name: "<", effect: "( a b -- flag )", description: "True if a is less than b" },
+    BuiltinInfo { name: ">", effect: "( a b -- flag )", description: "True if a is greater than b" },
+    BuiltinInfo { name: "and", effect: "( a b -- a&b )", description: "Bitwise and" },
+    BuiltinInfo { name: "or", effect: "( a b -- a|b )", description: "Bitwise or" },
+    BuiltinInfo { name: "not", effect: "( flag -- flag' )", description: "True if the input is 0, else false" },
+    BuiltinInfo { name: "depth", effect: "( -- n )", description: "Push the number of items on the stack" },
+    BuiltinInfo { name: "abs", effect: "( n -- |n| )", description: "Absolute value; i32::MIN stays i32::MIN" },
+    BuiltinInfo { name: "if", effect: "( flag -- )", description: "Run the following code up to else or then only if flag is nonzero" },
+    BuiltinInfo { name: "else", effect: "( -- )", description: "Start the branch taken when the if flag was zero" },
+    BuiltinInfo { name: "then", effect: "( -- )", description: "End an if or if/else" },
+    BuiltinInfo { name: "begin", effect: "( -- )", description: "Start a begin ... until loop" },
+    BuiltinInfo { name: "until", effect: "( flag -- )", description: "Loop back to begin while flag is zero" },
+    BuiltinInfo { name: ".", effect: "( n -- )", description: "Print n followed by a space" },
+    BuiltinInfo { name: "emit", effect: "( c -- )", description: "Print the Unicode character with code point c, as UTF-8" },
+    BuiltinInfo { name: "exit", effect: "( -- )", description: "Return from the current word" },
+    BuiltinInfo { name: "forget", effect: "( -- )", description: "forget <name> removes name and every word defined after it" },
+    BuiltinInfo { name: "state", effect: "( -- flag )", description: "Push -1 if a definition is being compiled when this runs, else 0" },
+    BuiltinInfo { name: "test", effect: "( -- )", description: "Start a named test block: test <name> ... expect ... endtest" },
+    BuiltinInfo { name: "expect", effect: "( -- )", description: "End a test's setup code and start its expected values" },
+    BuiltinInfo { name: "endtest", effect: "( setup... expected... -- )", description: "Compare a test's results with its expected values" },
+    BuiltinInfo { name: "iota", effect: "( n -- 0 1 ... n-1 )", description: "Push the integers below n" },
+    BuiltinInfo { name: "fm/mod", effect: "( n1 n2 -- rem quot )", description: "Floored division" },
+    BuiltinInfo { name: "sm/rem", effect: "( n1 n2 -- rem quot )", description: "Symmetric division" },
+    BuiltinInfo { name: "@", effect: "( addr -- n )", description: "Fetch the memory cell at addr" },
+    BuiltinInfo { name: "!", effect: "( n addr -- )", description: "Store n in the memory cell at addr" },
+    BuiltinInfo { name: "variable", effect: "( -- )", description: "variable <name> reserves a cell; name pushes its address" },
+    BuiltinInfo { name: "allot", effect: "( -- )", description: "<n> allot reserves n more cells after the last variable" },
+];
+
+/// Built-ins the parser handles itself rather than compiling to a single
+/// instruction. Unlike other built-ins these can't be redefined.
+const PARSING_WORDS: &[&str] = &[
+    "if", "else", "then", "begin", "until", "forget", "test", "expect", "endtest",
+    "variable", "allot",
+];
+
+/// The instruction a built-in compiles to, by its name in `BUILTINS`. The
+/// `PARSING_WORDS` are handled by the parser instead.
+fn builtin_instruction(name: &str) -> Instruction {
+    match name {
+        "dup" => Instruction::Dup,
+        "drop" => Instruction::Drop,
+        "swap" => Instruction::Swap,
+        "over" => Instruction::Over,
+        "tuck" => Instruction::Tuck,
+        "+" => Instruction::Add,
+        "-" => Instruction::Sub,
+        "*" => Instruction::Mul,
+        "/" => Instruction::Div,
+        "mod" => Instruction::Mod,
+        "=" => Instruction::Eq,
+        "<" => Instruction::Lt,
+        ">" => Instruction::Gt,
+        "and" => Instruction::And,
+        "or" => Instruction::Or,
+        "not" => Instruction::Not,
+        "depth" => Instruction::Depth,
+        "state" => Instruction::State,
+        "abs" => Instruction::Abs,
+        "exit" => Instruction::Return,
+        "." => Instruction::Dot,
+        "emit" => Instruction::Emit,
+        "iota" => Instruction::Iota,
+        "fm/mod" => Instruction::FmMod,
+        "sm/rem" => Instruction::SmRem,
+        "@" => Instruction::Fetch,
+        "!" => Instruction::Store,
+        _ => unreachable!("built-in without an instruction: {}", name),
+    }
+}
+
+/// Converts a condition to a Forth flag: -1 for true, 0 for false.
+fn flag(condition: bool) -> i32 {
+    if condition { -1 } else { 0 }
+}
+
+/// Looks up the stack effect and description of a built-in word.
+pub fn builtin_info(name: &str) -> Option<BuiltinInfo> {
+    BUILTINS.iter().find(|info| info.name == name).copied()
+}
+
+/// Convenience words defined in Forth itself. `VM::new_with_prelude` loads
+/// these ahead of user code.
+pub const PRELUDE: &str = "
+: 1+ 1 + ;
+: 1- -1 + ;
+: 2* 2 * ;
+: negate -1 * ;
+: true -1 ;
+: false 0 ;
+";
+
+/// How many items the data stack may hold before `VmError::StackOverflow`,
+/// unless changed with `VM::with_stack_limit`.
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 20;
+
+/// How deeply calls may nest before `VmError::ReturnStackOverflow`, unless
+/// changed with `VM::with_return_stack_limit`.
+pub const DEFAULT_RETURN_STACK_LIMIT: usize = 1024;
+
+/// Most steps `VM::to_html` records, so a program that never halts still
+/// gives a page.
+pub const HTML_MAX_STEPS: usize = 10_000;
+
+#[derive(Debug)]
+pub struct VM<W = std::io::Stdout> {
+    stack: Vec<i32>,
+    program: Vec<Instruction>,
+    ip: usize,
+    return_stack: Vec<usize>,
+    dictionary: HashMap<String, usize>,
+    /// Entry addresses of every definition of each word, oldest first.
+    history: HashMap<String, Vec<usize>>,
+    /// Entry addresses that have been called, when coverage is enabled.
+    covered: Option<HashSet<usize>>,
+    /// Stack depths saved by `TestBegin`/`TestExpect` for open test blocks.
+    test_marks: Vec<usize>,
+    test_results: TestResults,
+    /// Instructions dispatched so far.
+    steps: usize,
+    /// Maximum instructions a whole run may dispatch. `None` means unlimited.
+    max_steps: Option<usize>,
+    /// Maximum instructions a single word call may execute, including the
+    /// words it calls. `None` means unlimited.
+    word_step_limit: Option<usize>,
+    /// Deepest the data stack may grow, `DEFAULT_STACK_LIMIT` unless changed.
+    /// `None` means unlimited.
+    stack_limit: Option<usize>,
+    /// Deepest calls may nest, `DEFAULT_RETURN_STACK_LIMIT` unless changed.
+    /// `None` means unlimited.
+    return_stack_limit: Option<usize>,
+    /// Whether the parser had a definition open when this code was linked,
+    /// as read by `State`.
+    compiling: bool,
+    /// Data space cells, addressed from 0, reserved by `variable` and `allot`.
+    memory: Vec<i32>,
+    /// Which cells have been stored to, when poison mode is on. Cells past
+    /// its end haven't been.
+    written: Option<Vec<bool>>,
+    /// Tags of the stack and memory values, when tagged-cell mode is on.
+    tags: Option<Tags>,
+    /// One `(entry address, steps at entry)` per active call, parallel to
+    /// the return stack.
+    frames: Vec<(usize, usize)>,
+    /// Example annotations of the linked words, for `run_examples`.
+    examples: Vec<Example>,
+    /// Whether `emit` escapes control characters rather than writing them.
+    safe_output: bool,
+    /// Stack depth past which a one-time warning is written, well before
+    /// `stack_limit` stops the run.
+    soft_stack_warn: Option<usize>,
+    /// Whether that warning has been written.
+    stack_warned: bool,
+    /// State before each recent step, newest last, for `step_back`. At most
+    /// `undo_limit` are kept; 0 turns recording off.
+    undo_log: VecDeque<Undo>,
+    undo_limit: usize,
+    /// Where `.`, `emit` and test reports are written; stdout by default.
+    out: W,
+}
+
+/// Whether a value is a plain number or the address of a memory cell, in
+/// tagged-cell mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Int,
+    Addr,
+}
+
+/// A stack value with its tag, as `VM::tagged_stack` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub value: i32,
+    pub tag: Tag,
+}
+
+/// One tag per stack value, bottom first, and per memory cell that has been
+/// stored to. Cells past the end of `memory` hold numbers.
+#[derive(Debug, Clone, Default)]
+struct Tags {
+    stack: Vec<Tag>,
+    memory: Vec<Tag>,
+}
+
+/// What a step may change, as it was before the step.
+#[derive(Debug, Clone)]
+struct Undo {
+    stack: Vec<i32>,
+    ip: usize,
+    return_stack: Vec<usize>,
+    frames: Vec<(usize, usize)>,
+    test_marks: Vec<usize>,
+    test_results: TestResults,
+    steps: usize,
+    stack_warned: bool,
+    tags: Option<Tags>,
+    /// The memory cell a `Store` is about to overwrite, its old value and
+    /// whether it had been stored to before.
+    cell: Option<(usize, i32, bool)>,
+}
+
+/// Outcome of a single `VM::step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    Halted,
+}
+
+/// Tally of `test ... expect ... endtest` blocks run so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Outcome of `VM::run_examples`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestReport {
+    pub results: TestResults,
+    /// One line per failed example, saying what it left instead.
+    pub failures: Vec<String>,
+}
+
+/// A `( 3 -- 9 )` annotation in a definition: calling `word` on a stack
+/// holding `input` should leave `output`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Example {
+    word: String,
+    input: Vec<i32>,
+    output: Vec<i32>,
+    line: usize,
+}
+
+impl VM {
+    pub fn new(program: Vec<Instruction>) -> Self {
+        Self {
+            stack: Vec::new(),
+            program,
+            ip: 0,
+            return_stack: Vec::new(),
+            dictionary: HashMap::new(),
+            history: HashMap::new(),
+            covered: None,
+            test_marks: Vec::new(),
+            test_results: TestResults::default(),
+            steps: 0,
+            max_steps: None,
+            word_step_limit: None,
+            stack_limit: Some(DEFAULT_STACK_LIMIT),
+            return_stack_limit: Some(DEFAULT_RETURN_STACK_LIMIT),
+            compiling: false,
+            memory: Vec::new(),
+            written: None,
+            tags: None,
+            frames: Vec::new(),
+            examples: Vec::new(),
+            safe_output: false,
+            soft_stack_warn: None,
+            stack_warned: false,
+            undo_log: VecDeque::new(),
+            undo_limit: 0,
+            out: std::io::stdout(),
+        }
+    }
+
+    /// Parses the `PRELUDE` followed by `source` into a VM, so prelude words
+    /// are available to the user code.
+    pub fn new_with_prelude(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser::new();
+        parser.parse(PRELUDE).expect("prelude should parse");
+        // Report errors against the user's own line numbers
+        parser.line = 0;
+        parser.parse(source)?;
+        Ok(Self::from_parser(parser))
+    }
+
+    /// Like `new`, but reserves room for `stack_cap` data stack items and
+    /// `return_cap` nested calls up front, so programs that stay within
+    /// those depths never reallocate either stack.
+    pub fn with_capacity(program: Vec<Instruction>, stack_cap: usize, return_cap: usize) -> Self {
+        let mut vm = Self::new(program);
+        vm.stack.reserve(stack_cap);
+        vm.return_stack.reserve(return_cap);
+        vm.frames.reserve(return_cap);
+        vm
+    }
+
+    /// Finalizes `parser` into a ready-to-run VM, carrying over the
+    /// dictionary and definition history.
+    pub fn from_parser(parser: Parser) -> Self {
+        parser.link()
+    }
+}
+
+impl<W: Write> VM<W> {
+    /// Sends the VM's output to `out` instead of stdout.
+    pub fn with_output<X: Write>(self, out: X) -> VM<X> {
+        VM {
+            stack: self.stack,
+            program: self.program,
+            ip: self.ip,
+            return_stack: self.return_stack,
+            dictionary: self.dictionary,
+            history: self.history,
+            covered: self.covered,
+            test_marks: self.test_marks,
+            test_results: self.test_results,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            word_step_limit: self.word_step_limit,
+            stack_limit: self.stack_limit,
+            return_stack_limit: self.return_stack_limit,
+            compiling: self.compiling,
+            memory: self.memory,
+            written: self.written,
+            tags: self.tags,
+            frames: self.frames,
+            examples: self.examples,
+            safe_output: self.safe_output,
+            soft_stack_warn: self.soft_stack_warn,
+            stack_warned: self.stack_warned,
+            undo_log: self.undo_log,
+            undo_limit: self.undo_limit,
+            out,
+        }
+    }
+
+    /// The output sink.
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    /// Consumes the VM and returns its output sink, e.g. to read back what
+    /// a run wrote to a `Vec<u8>`.
+    pub fn into_output(self) -> W {
+        self.out
+    }
+
+    /// Caps the total instructions `run` may dispatch, so runaway programs
+    /// stop with `VmError::StepLimitExceeded`.
+    pub fn with_step_limit(mut self, limit: usize) -> Self {
+        self.max_steps = Some(limit);
+        self
+    }
+
+    /// Caps the instructions any single word call may execute.
+    pub fn with_word_step_limit(mut self, limit: usize) -> Self {
+        self.word_step_limit = Some(limit);
+        self
+    }
+
+    /// Caps the data stack at `limit` items, replacing the default of
+    /// `DEFAULT_STACK_LIMIT`; growing it further fails with
+    /// `VmError::StackOverflow`.
+    pub fn with_stack_limit(mut self, limit: usize) -> Self {
+        self.stack_limit = Some(limit);
+        self
+    }
+
+    /// Caps how deeply calls may nest, replacing the default of
+    /// `DEFAULT_RETURN_STACK_LIMIT`.
+    pub fn with_return_stack_limit(mut self, limit: usize) -> Self {
+        self.return_stack_limit = Some(limit);
+        self
+    }
+
+    /// Makes `emit` write control characters as `^G`-style escapes, DEL as
+    /// `^?` and the controls from 0x80 to 0x9F as `\xNN`, so stray values
+    /// can't upset a terminal. Newlines and tabs, and printable characters
+    /// beyond ASCII, are still written as they are.
+    pub fn with_safe_output(mut self) -> Self {
+        self.safe_output = true;
+        self
+    }
+
+    /// Writes a warning to the output sink, once, the first time the stack
+    /// grows past `depth` items. Unlike `with_stack_limit` the run carries
+    /// on; this is a hint that something is leaving values behind.
+    pub fn with_soft_stack_warn(mut self, depth: usize) -> Self {
+        self.soft_stack_warn = Some(depth);
+        self
+    }
+
+    /// Poison mode: makes `@` fail with `VmError::UninitializedRead` on a
+    /// cell that no `!` has stored to yet, instead of reading the 0 it
+    /// starts out as, to catch variables used before they are set.
+    pub fn with_poison(mut self) -> Self {
+        self.written = Some(Vec::new());
+        self
+    }
+
+    /// Tagged-cell mode: tags every stack value as a number or an address,
+    /// and makes `@` and `!` fail with `VmError::NotAnAddress` when given a
+    /// number. Addresses come from `variable` words, survive the stack
+    /// shuffles and `!`/`@` round trips, and stay addresses when a number is
+    /// added or subtracted, so `xs 2 + @` works. Everything else produces
+    /// numbers.
+    pub fn with_tagged_cells(mut self) -> Self {
+        self.tags = Some(Tags { stack: vec![Tag::Int; self.stack.len()], memory: Vec::new() });
+        self
+    }
+
+    /// Records the state before each of the last `limit` steps, so
+    /// `step_back` can undo them.
+    pub fn with_undo_limit(mut self, limit: usize) -> Self {
+        self.undo_limit = limit;
+        self
+    }
+
+    /// Enables recording of which words get called, for `coverage`.
+    pub fn with_coverage(mut self) -> Self {
+        self.covered = Some(HashSet::new());
+        self
+    }
+
+    pub fn add_word(&mut self, name: &str, address: usize) {
+        self.dictionary.insert(name.to_string(), address);
+    }
+
+    /// Runs until the program halts or fails.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.step()? == StepResult::Running {}
+        Ok(())
+    }
+
+    /// Executes exactly one instruction. Stepping a halted VM keeps
+    /// reporting `Halted`.
+    pub fn step(&mut self) -> Result<StepResult, VmError> {
+        let undo = (self.undo_limit > 0).then(|| self.undo_entry());
+        let tagged = self.check_tags()?;
+        let result = self.dispatch()?;
+        if let Some((instr, depth, top)) = tagged {
+            self.retag(&instr, depth, top);
+        }
+        if let Some(undo) = undo
+            && result == StepResult::Running
+        {
+            if self.undo_log.len() == self.undo_limit {
+                self.undo_log.pop_front();
+            }
+            self.undo_log.push_back(undo);
+        }
+        if let Some(depth) = self.soft_stack_warn
+            && !self.stack_warned
+            && self.stack.len() > depth
+        {
+            self.stack_warned = true;
+            writeln!(self.out, "warning: stack depth {} is past {}; are values being left behind?", self.stack.len(), depth)?;
+        }
+        Ok(result)
+    }
+
+    /// Undoes the most recent step recorded since `with_undo_limit`, putting
+    /// back the stacks, memory, position and step count. Output already
+    /// written and coverage stay as they are. Returns `false` when there is
+    /// nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.undo_log.pop_back() else { return false };
+        self.stack = undo.stack;
+        self.ip = undo.ip;
+        self.return_stack = undo.return_stack;
+        self.frames = undo.frames;
+        self.test_marks = undo.test_marks;
+        self.test_results = undo.test_results;
+        self.steps = undo.steps;
+        self.stack_warned = undo.stack_warned;
+        self.tags = undo.tags;
+        if let Some((cell, value, was_written)) = undo.cell {
+            self.memory[cell] = value;
+            if let Some(written) = &mut self.written
+                && cell < written.len()
+            {
+                written[cell] = was_written;
+            }
+        }
+        true
+    }
+
+    /// The state the next step may change, for `step_back`.
+    fn undo_entry(&self) -> Undo {
+        let cell = match self.program.get(self.ip) {
+            Some(Instruction::Store) => self.stack.last()
+                .and_then(|&addr| self.cell(addr).ok())
+                .map(|cell| (cell, self.memory[cell], self.is_written(cell))),
+            _ => None,
+        };
+        Undo {
+            stack: self.stack.clone(),
+            ip: self.ip,
+            return_stack: self.return_stack.clone(),
+            frames: self.frames.clone(),
+            test_marks: self.test_marks.clone(),
+            test_results: self.test_results,
+            steps: self.steps,
+            stack_warned: self.stack_warned,
+            tags: self.tags.clone(),
+            cell,
+        }
+    }
+
+    /// In tagged-cell mode, fails if the next instruction is an `@` or `!`
+    /// given a number as its address. Otherwise returns the instruction, the
+    /// stack depth and its top value, for `retag` once it has run.
+    fn check_tags(&self) -> Result<Option<(Instruction, usize, Option<i32>)>, VmError> {
+        let (Some(tags), Some(instr)) = (&self.tags, self.program.get(self.ip)) else { return Ok(None) };
+        let top = self.stack.last().copied();
+        if let (Instruction::Fetch | Instruction::Store, Some(value), Some(Tag::Int)) = (instr, top, tags.stack.last()) {
+            return Err(VmError::NotAnAddress { value, ip: self.ip });
+        }
+        Ok(Some((instr.clone(), self.stack.len(), top)))
+    }
+
+    /// Updates the tags after `instr` ran on a stack `depth` deep whose top
+    /// value was `top`.
+    fn retag(&mut self, instr: &Instruction, depth: usize, top: Option<i32>) {
+        let Some(tags) = &mut self.tags else { return };
+        let stack = &mut tags.stack;
+        let n = depth;
+        match instr {
+            Instruction::Address(_) => stack.push(Tag::Addr),
+            Instruction::Dup => stack.push(stack[n - 1]),
+            Instruction::Drop => stack.truncate(n - 1),
+            Instruction::Swap => stack.swap(n - 2, n - 1),
+            Instruction::Over => stack.push(stack[n - 2]),
+            Instruction::Rot => stack[n - 3..].rotate_left(1),
+            Instruction::Nip => {
+                stack.remove(n - 2);
+            }
+            Instruction::Tuck => stack.insert(n - 2, stack[n - 1]),
+            Instruction::TwoDup => stack.extend_from_within(n - 2..),
+            Instruction::TwoDrop => stack.truncate(n - 2),
+            Instruction::TwoSwap => stack[n - 4..].rotate_left(2),
+            // An address offset by a number is still an address
+            Instruction::Add | Instruction::Sub => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                let addr = match (a, b) {
+                    (Tag::Addr, Tag::Int) => Tag::Addr,
+                    (Tag::Int, Tag::Addr) if matches!(instr, Instruction::Add) => Tag::Addr,
+                    _ => Tag::Int,
+                };
+                stack.push(addr);
+            }
+            Instruction::Fetch => {
+                let cell = top.unwrap() as usize;
+                stack[n - 1] = tags.memory.get(cell).copied().unwrap_or(Tag::Int);
+            }
+            Instruction::Store => {
+                let cell = top.unwrap() as usize;
+                let value = stack[n - 2];
+                stack.truncate(n - 2);
+                if tags.memory.len() <= cell {
+                    tags.memory.resize(cell + 1, Tag::Int);
+                }
+                tags.memory[cell] = value;
+            }
+            // The rest pop their operands and push only numbers
+            _ => {
+                let pops = match instr {
+                    Instruction::Mul | Instruction::Div | Instruction::Mod | Instruction::Eq | Instruction::Lt
+                    | Instruction::Gt | Instruction::And | Instruction::Or | Instruction::FmMod | Instruction::SmRem => 2,
+                    Instruction::Not | Instruction::Abs | Instruction::Iota | Instruction::Dot | Instruction::Emit
+                    | Instruction::IfZero(_) => 1,
+                    Instruction::TestEnd(_) => depth - self.stack.len(),
+                    _ => 0,
+                };
+                stack.truncate(n - pops);
+                stack.resize(self.stack.len(), Tag::Int);
+            }
+        }
+    }
+
+    /// Executes the instruction at `ip`, for `step`.
+    fn dispatch(&mut self) -> Result<StepResult, VmError> {
+        if self.ip >= self.program.len() {
+            self.out.flush()?;
+            return Ok(StepResult::Halted);
+        }
+        self.steps += 1;
+        if self.max_steps.is_some_and(|max| self.steps > max) {
+            return Err(VmError::StepLimitExceeded);
+        }
+        if let (Some(limit), Some(&(entry, start))) = (self.word_step_limit, self.frames.last())
+            && self.steps - start > limit
+        {
+            return Err(VmError::StepBudgetExceeded { word: self.word_name(entry) });
+        }
+
+        match &self.program[self.ip] {
+            Instruction::Push(value) | Instruction::Address(value) => {
+                self.room(1, "PUSH")?;
+                self.stack.push(*value);
+            }
+            Instruction::PushN(values) => {
+                self.room(values.len(), "PUSH")?;
+                self.stack.extend_from_slice(values);
+            }
+            Instruction::Add => {
+                let (a, b) = self.pop2("ADD")?;
+                self.stack.push(a.wrapping_add(b));
+            }
+            Instruction::Sub => {
+                let (a, b) = self.pop2("SUB")?;
+                self.stack.push(a.wrapping_sub(b));
+            }
+            Instruction::Mul => {
+                let (a, b) = self.pop2("MUL")?;
+                self.stack.push(a.wrapping_mul(b));
+            }
+            Instruction::Div => {
+                let (a, b) = self.pop2("DIV")?;
+                if b == 0 {
+                    return Err(VmError::DivideByZero { ip: self.ip });
+                }
+                self.stack.push(a.wrapping_div(b));
+            }
+            Instruction::Mod => {
+                let (a, b) = self.pop2("MOD")?;
+                if b == 0 {
+                    return Err(VmError::DivideByZero { ip: self.ip });
+                }
+                self.stack.push(a.wrapping_rem(b));
+            }
+            Instruction::Eq => {
+                let (a, b) = self.pop2("EQ")?;
+                self.stack.push(flag(a == b));
+            }
+            Instruction::Lt => {
+                let (a, b) = self.pop2("LT")?;
+                self.stack.push(flag(a < b));
+            }
+            Instruction::Gt => {
+                let (a, b) = self.pop2("GT")?;
+                self.stack.push(flag(a > b));
+            }
+            Instruction::And => {
+                let (a, b) = self.pop2("AND")?;
+                self.stack.push(a & b);
+            }
+            Instruction::Or => {
+                let (a, b) = self.pop2("OR")?;
+                self.stack.push(a | b);
+            }
+            Instruction::Not => {
+                let a = self.pop("NOT")?;
+                self.stack.push(flag(a == 0));
+            }
+            Instruction::Dup => {
+                self.require(1, "DUP")?;
+                self.room(1, "DUP")?;
+                let top = *self.stack.last().unwrap();
+                self.stack.push(top);
+            }
+            Instruction::Drop => {
+                self.pop("DROP")?;
+            }
+            Instruction::Swap => {
+                let (a, b) = self.pop2("SWAP")?;
+                self.stack.push(b);
+                self.stack.push(a);
+            }
+            Instruction::Over => {
+                self.require(2, "OVER")?;
+                self.room(1, "OVER")?;
+                let val = self.stack[self.stack.len() - 2];
+                self.stack.push(val);
+            }
+            Instruction::Rot => {
+                self.require(3, "ROT")?;
+                let c = self.stack.pop().unwrap();
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                self.stack.push(b);
+                self.stack.push(c);
+                self.stack.push(a);
+            }
+            Instruction::Nip => {
+                self.require(2, "NIP")?;
+                let top = self.stack.pop().unwrap();
+                self.stack.pop(); // discard second
+                self.stack.push(top);
+            }
+            Instruction::Tuck => {
+                // ( a b -- b a b )
+                self.require(2, "TUCK")?;
+                self.room(1, "TUCK")?;
+                let (a, b) = self.pop2("TUCK")?;
+                self.stack.push(b);
+                self.stack.push(a);
+                self.stack.push(b);
+            }
+            Instruction::TwoDup => {
+                self.require(2, "2DUP")?;
+                self.room(2, "2DUP")?;
+                let len = self.stack.len();
+                self.stack.push(self.stack[len - 2]);
+                self.stack.push(self.stack[len - 1]);
+            }
+            Instruction::TwoDrop => {
+                self.require(2, "2DROP")?;
+                self.stack.pop();
+                self.stack.pop();
+            }
+            Instruction::TwoSwap => {
+                self.require(4, "2SWAP")?;
+                let d = self.stack.pop().unwrap();
+                let c = self.stack.pop().unwrap();
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                self.stack.push(c);
+                self.stack.push(d);
+                self.stack.push(a);
+                self.stack.push(b);
+            }
+            Instruction::State => {
+                self.room(1, "STATE")?;
+                self.stack.push(flag(self.compiling));
+            }
+            Instruction::Depth => {
+                self.room(1, "DEPTH")?;
+                let depth = self.stack.len() as i32;
+                self.stack.push(depth);
+            }
+            Instruction::Abs => {
+                let x = self.pop("ABS")?;
+                let mask = x >> 31; // 0 for non-negative, -1 for negative
+                self.stack.push((x ^ mask).wrapping_sub(mask));
+            }
+            Instruction::FmMod => {
+                let (a, b) = self.pop2("FM/MOD")?;
+                if b == 0 {
+                    return Err(VmError::DivideByZero { ip: self.ip });
+                }
+                let mut quot = a.wrapping_div(b);
+                let mut rem = a.wrapping_rem(b);
+                if rem != 0 && (rem < 0) != (b < 0) {
+                    quot -= 1;
+                    rem += b;
+                }
+                self.stack.push(rem);
+                self.stack.push(quot);
+            }
+            Instruction::SmRem => {
+                let (a, b) = self.pop2("SM/REM")?;
+                if b == 0 {
+                    return Err(VmError::DivideByZero { ip: self.ip });
+                }
+                self.stack.push(a.wrapping_rem(b));
+                self.stack.push(a.wrapping_div(b));
+            }
+            Instruction::Iota => {
+                self.require(1, "IOTA")?;
+                let n = *self.stack.last().unwrap();
+                if n < 0 {
+                    return Err(VmError::NegativeCount { op: "IOTA", count: n, ip: self.ip });
+                }
+                // The sequence replaces the count
+                self.room((n as usize).saturating_sub(1), "IOTA")?;
+                self.stack.pop();
+                self.stack.extend(0..n);
+            }
+            Instruction::Call(addr) => {
+                let addr = *addr;
+                self.enter(addr)?;
+                return Ok(StepResult::Running);
+            }
+            Instruction::RelCall(offset) => {
+                let addr = ((self.ip as isize) + offset) as usize;
+                self.enter(addr)?;
+                return Ok(StepResult::Running);
+            }
+            Instruction::CallWord(name) => {
+                let addr = *self.dictionary.get(name)
+                    .ok_or_else(|| VmError::UnknownWord(name.clone()))?;
+                self.enter(addr)?;
+                return Ok(StepResult::Running);
+            }
+            Instruction::Return => {
+                let ret = self.return_stack.pop().ok_or(VmError::ReturnStackUnderflow)?;
+                self.frames.pop();
+                self.ip = ret;
+                return Ok(StepResult::Running);
+            }
+            Instruction::IfZero(offset) => {
+                let offset = *offset;
+                let cond = self.pop("IFZERO")?;
+                if cond == 0 {
+                    self.ip = ((self.ip as isize) + offset) as usize;
+                    return Ok(StepResult::Running); // skip ip += 1
+                }
+            }
+            Instruction::Jump(offset) => {
+                self.ip = ((self.ip as isize) + offset) as usize;
+                return Ok(StepResult::Running);
+            }
+            Instruction::TestBegin | Instruction::TestExpect => {
+                self.test_marks.push(self.stack.len());
+            }
+            Instruction::TestEnd(name) => {
+                if self.test_marks.len() < 2 {
+                    return Err(VmError::UnbalancedTest { ip: self.ip });
+                }
+                let expect = self.test_marks.pop().unwrap();
+                let begin = self.test_marks.pop().unwrap();
+                // Code after `expect` may have dropped below either mark,
+                // which fails the test
+                let intact = begin <= expect && expect <= self.stack.len();
+                let expect = expect.min(self.stack.len());
+                let begin = begin.min(expect);
+                let actual = &self.stack[begin..expect];
+                let expected = &self.stack[expect..];
+                if intact && actual == expected {
+                    self.test_results.passed += 1;
+                    writeln!(self.out, "test {}: ok", name)?;
+                } else {
+                    self.test_results.failed += 1;
+                    writeln!(self.out, "test {}: FAILED (expected {:?}, got {:?})", name, expected, actual)?;
+                }
+                self.stack.truncate(begin);
+            }
+            Instruction::Dot => {
+                let value = self.pop("DOT")?;
+                write!(self.out, "{} ", value)?;
+            }
+            Instruction::Emit => {
+                self.require(1, "EMIT")?;
+                let value = *self.stack.last().unwrap();
+                let c = u32::try_from(value).ok()
+                    .and_then(char::from_u32)
+                    .ok_or(VmError::InvalidChar { value, ip: self.ip })?;
+                self.stack.pop();
+                self.emit(c)?;
+            }
+            Instruction::Fetch => {
+                self.require(1, "FETCH")?;
+                let addr = *self.stack.last().unwrap();
+                let cell = self.cell(addr)?;
+                if self.written.is_some() && !self.is_written(cell) {
+                    return Err(VmError::UninitializedRead { addr, ip: self.ip });
+                }
+                self.stack.pop();
+                self.stack.push(self.memory[cell]);
+            }
+            Instruction::Store => {
+                self.require(2, "STORE")?;
+                let cell = self.cell(*self.stack.last().unwrap())?;
+                let (value, _) = self.pop2("STORE")?;
+                self.memory[cell] = value;
+                if let Some(written) = &mut self.written {
+                    if written.len() <= cell {
+                        written.resize(cell + 1, false);
+                    }
+                    written[cell] = true;
+                }
+            }
+            Instruction::Halt => {
+                self.out.flush()?;
+                return Ok(StepResult::Halted);
+            }
+        }
+
+        self.ip += 1;
+        Ok(StepResult::Running)
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Entry address of each word currently defined.
+    pub fn dictionary(&self) -> &HashMap<String, usize> {
+        &self.dictionary
+    }
+
+    pub fn memory(&self) -> &[i32] {
+        &self.memory
+    }
+
+    /// Instructions dispatched so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Fails with `StackUnderflow` unless at least `n` items are on the stack.
+    fn require(&self, n: usize, op: &'static str) -> Result<(), VmError> {
+        if self.stack.len() < n {
+            return Err(VmError::StackUnderflow { op, ip: self.ip });
+        }
+        Ok(())
+    }
+
+    /// Fails with `StackOverflow` unless `n` more items fit under `stack_limit`.
+    fn room(&self, n: usize, op: &'static str) -> Result<(), VmError> {
+        if self.stack_limit.is_some_and(|limit| self.stack.len() + n > limit) {
+            return Err(VmError::StackOverflow { op, ip: self.ip });
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self, op: &'static str) -> Result<i32, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { op, ip: self.ip })
+    }
+
+    /// Pops the top two items as `(second, top)`, leaving the stack untouched
+    /// on underflow.
+    fn pop2(&mut self, op: &'static str) -> Result<(i32, i32), VmError> {
+        self.require(2, op)?;
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        Ok((a, b))
+    }
+
+    /// Writes `c` to the output sink as UTF-8, escaped if `safe_output` is
+    /// on and it is a control character.
+    fn emit(&mut self, c: char) -> Result<(), VmError> {
+        match c {
+            _ if !self.safe_output || !c.is_control() => write!(self.out, "{}", c)?,
+            '\n' | '\t' => write!(self.out, "{}", c)?,
+            '\0'..='\x1f' => write!(self.out, "^{}", (c as u8 + 0x40) as char)?,
+            '\x7f' => write!(self.out, "^?")?,
+            _ => write!(self.out, "\\x{:02X}", c as u32)?,
+        }
+        Ok(())
+    }
+
+    /// The index into `memory` for `addr`, or `InvalidAddress` if it is out
+    /// of range.
+    fn cell(&self, addr: i32) -> Result<usize, VmError> {
+        usize::try_from(addr).ok()
+            .filter(|&cell| cell < self.memory.len())
+            .ok_or(VmError::InvalidAddress { addr, ip: self.ip })
+    }
+
+    /// The data stack with each value's tag, bottom first, in tagged-cell
+    /// mode.
+    pub fn tagged_stack(&self) -> Option<Vec<Cell>> {
+        let tags = self.tags.as_ref()?;
+        Some(self.stack.iter().zip(&tags.stack).map(|(&value, &tag)| Cell { value, tag }).collect())
+    }
+
+    /// Whether poison mode has seen a store to `cell`.
+    fn is_written(&self, cell: usize) -> bool {
+        self.written.as_ref().is_some_and(|written| written.get(cell) == Some(&true))
+    }
+
+    /// Checks every `( in -- out )` example annotation by calling its word on
+    /// a stack holding just `in`, which should leave just `out`. The stacks,
+    /// memory and position of the VM are restored after each example, and
+    /// the example steps aren't recorded for `step_back`.
+    pub fn run_examples(&mut self) -> TestReport {
+        let spaced = |values: &[i32]| values.iter().map(|v| format!("{} ", v)).collect::<String>();
+        let mut report = TestReport::default();
+        let undo_limit = std::mem::replace(&mut self.undo_limit, 0);
+        for example in self.examples.clone() {
+            let Some(&addr) = self.dictionary.get(&example.word) else { continue };
+            let stack = std::mem::replace(&mut self.stack, example.input.clone());
+            let return_stack = std::mem::take(&mut self.return_stack);
+            let frames = std::mem::take(&mut self.frames);
+            let test_marks = std::mem::take(&mut self.test_marks);
+            let memory = self.memory.clone();
+            let written = self.written.clone();
+            let tags = self.tags.clone();
+            if let Some(tags) = &mut self.tags {
+                tags.stack = vec![Tag::Int; example.input.len()];
+            }
+            let (ip, steps) = (self.ip, self.steps);
+
+            // `enter` returns to the address after ip, which is past the end
+            // of the program, so the run halts once the word returns
+            self.ip = self.program.len() - 1;
+            self.steps = 0;
+            let outcome = self.enter(addr).and_then(|()| self.run());
+            let label = format!("{} ( {}-- {}) on line {}", example.word, spaced(&example.input), spaced(&example.output), example.line);
+            match outcome {
+                Ok(()) if self.stack == example.output => report.results.passed += 1,
+                Ok(()) => {
+                    report.results.failed += 1;
+                    report.failures.push(format!("{}: got {:?}", label, self.stack));
+                }
+                Err(err) => {
+                    report.results.failed += 1;
+                    report.failures.push(format!("{}: {}", label, err));
+                }
+            }
+
+            self.stack = stack;
+            self.return_stack = return_stack;
+            self.frames = frames;
+            self.test_marks = test_marks;
+            self.memory = memory;
+            self.written = written;
+            self.tags = tags;
+            self.ip = ip;
+            self.steps = steps;
+        }
+        self.undo_limit = undo_limit;
+        report
+    }
+
+    /// Splits the dictionary into words that were called at least once and
+    /// words that never were, both sorted. Everything counts as uncovered
+    /// unless the VM was built `with_coverage`.
+    pub fn coverage(&self) -> (Vec<String>, Vec<String>) {
+        let mut covered = Vec::new();
+        let mut uncovered = Vec::new();
+        for (name, addr) in &self.dictionary {
+            if self.covered.as_ref().is_some_and(|set| set.contains(addr)) {
+                covered.push(name.clone());
+            } else {
+                uncovered.push(name.clone());
+            }
+        }
+        covered.sort();
+        uncovered.sort();
+        (covered, uncovered)
+    }
+
+    /// A human-readable summary of the stacks, memory and the dictionary.
+    pub fn dump_state(&self) -> String {
+        let mut words: Vec<(&String, &usize)> = self.dictionary.iter().collect();
+        words.sort_by_key(|(name, addr)| (**addr, *name));
+        let mut out = format!("Stack: {:?}\nReturn stack: {:?}\nMemory: {} cells {:?}\nDictionary:\n",
+            self.stack, self.return_stack, self.memory.len(), self.memory);
+        for (name, addr) in words {
+            out.push_str(&format!("  {} @ {}\n", name, addr));
+        }
+        out
+    }
+
+    /// A self-contained HTML page for stepping through the rest of this run:
+    /// the program listing next to the stack, with buttons to move between
+    /// steps. The steps are worked out here, on a copy of the VM that runs
+    /// from its current state for at most `HTML_MAX_STEPS` steps, and
+    /// embedded in the page as JSON. Output the run would write is
+    /// discarded, and this VM is left as it is.
+    pub fn to_html(&self) -> String {
+        let snapshot = |vm: &VM<std::io::Sink>| {
+            let stack = vm.stack.iter().map(i32::to_string).collect::<Vec<_>>().join(",");
+            format!("{{\"ip\":{},\"stack\":[{}]}}", vm.ip, stack)
+        };
+        let mut replay = self.replica();
+        let mut snapshots = vec![snapshot(&replay)];
+        let mut error = None;
+        while snapshots.len() <= HTML_MAX_STEPS {
+            match replay.step() {
+                Ok(StepResult::Running) => snapshots.push(snapshot(&replay)),
+                Ok(StepResult::Halted) => break,
+                Err(err) => {
+                    error = Some(err.to_string());
+                    break;
+                }
+            }
+        }
+
+        let mut listing = String::new();
+        for line in disassemble(&self.program, &self.dictionary).lines() {
+            match line.split_whitespace().next().and_then(|ip| ip.parse::<usize>().ok()) {
+                Some(ip) => listing.push_str(&format!("<span id=\"ip-{}\">{}</span>\n", ip, escape_html(line))),
+                None => listing.push_str(&format!("{}\n", escape_html(line))),
+            }
+        }
+        let snapshots = format!("[{}]", snapshots.join(","));
+        let error = error.as_deref().map_or("null".to_string(), json_string);
+
+        // One pass over the template, so a placeholder spelled inside the
+        // listing, e.g. by a word's name, is left alone
+        let mut page = String::with_capacity(HTML_TEMPLATE.len() + listing.len() + snapshots.len());
+        let mut rest = HTML_TEMPLATE;
+        while let Some(start) = rest.find("{{") {
+            let end = start + rest[start..].find("}}").expect("placeholders are closed") + 2;
+            page.push_str(&rest[..start]);
+            page.push_str(match &rest[start..end] {
+                "{{listing}}" => &listing,
+                "{{snapshots}}" => &snapshots,
+                "{{error}}" => &error,
+                other => unreachable!("unknown placeholder {}", other),
+            });
+            rest = &rest[end..];
+        }
+        page.push_str(rest);
+        page
+    }
+
+    /// A copy of the VM's state that writes nowhere, for `to_html`.
+    fn replica(&self) -> VM<std::io::Sink> {
+        VM {
+            stack: self.stack.clone(),
+            program: self.program.clone(),
+            ip: self.ip,
+            return_stack: self.return_stack.clone(),
+            dictionary: self.dictionary.clone(),
+            history: self.history.clone(),
+            covered: self.covered.clone(),
+            test_marks: self.test_marks.clone(),
+            test_results: self.test_results,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            word_step_limit: self.word_step_limit,
+            stack_limit: self.stack_limit,
+            return_stack_limit: self.return_stack_limit,
+            compiling: self.compiling,
+            memory: self.memory.clone(),
+            written: self.written.clone(),
+            tags: self.tags.clone(),
+            frames: self.frames.clone(),
+            examples: self.examples.clone(),
+            safe_output: self.safe_output,
+            soft_stack_warn: self.soft_stack_warn,
+            stack_warned: self.stack_warned,
+            undo_log: VecDeque::new(),
+            undo_limit: 0,
+            out: std::io::sink(),
+        }
+    }
+
+    /// Pass/fail counts of the `test` blocks executed so far.
+    pub fn test_results(&self) -> TestResults {
+        self.test_results
+    }
+
+    /// All entry addresses `name` has been defined at, in definition order.
+    /// The last one is the definition currently in the dictionary.
+    pub fn definition_history(&self, name: &str) -> Vec<usize> {
+        self.history.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Replaces calls to words whose whole body is a single literal, such as
+    /// `: ten 10 ;` or a `variable`, with that literal. Folded calls no
+    /// longer reach the word, so run this after any `unused_words` or
+    /// `coverage` analysis that should count them.
+    pub fn fold_constants(&mut self) {
+        let literal_at = |program: &[Instruction], addr: usize| match program.get(addr..addr.saturating_add(2)) {
+            Some([literal @ (Instruction::Push(_) | Instruction::Address(_)), Instruction::Return]) => Some(literal.clone()),
+            _ => None,
+        };
+        for ip in 0..self.program.len() {
+            if let Some(literal) = self.call_target(ip).and_then(|addr| literal_at(&self.program, addr)) {
+                self.program[ip] = literal;
+            }
+        }
+    }
+
+    /// Replaces calls to small leaf words with a copy of the word's body.
+    /// A word qualifies when it has at most `max_len` instructions before its
+    /// `Return`, makes no calls, and only branches within itself. The original
+    /// definitions stay in place so they remain callable by name.
+    pub fn inline_small_words(&mut self, max_len: usize) {
+        let bodies: HashMap<usize, Vec<Instruction>> = self.dictionary.values()
+            .filter_map(|&addr| Some((addr, self.leaf_body(addr, max_len)?)))
+            .collect();
+        let targets: Vec<Option<usize>> = (0..self.program.len())
+            .map(|ip| self.call_target(ip))
+            .collect();
+        let program = std::mem::take(&mut self.program);
+        let (program, map) = rewrite(program, |ip, instr, out| {
+            match targets[ip].and_then(|addr| bodies.get(&addr)) {
+                // Branch offsets inside the body are relative, so a contiguous
+                // copy keeps them valid, including a jump to the dropped Return.
+                Some(body) => out.extend(body.iter().cloned().map(|instr| (instr, None))),
+                None => out.push((instr, Some(ip))),
+            }
+        });
+        self.program = program;
+        self.remap(&map);
+    }
+
+    /// The body of the word at `entry`, without its `Return`, if it is a leaf
+    /// word of at most `max_len` instructions whose branches stay inside it.
+    fn leaf_body(&self, entry: usize, max_len: usize) -> Option<Vec<Instruction>> {
+        let len = self.program.get(entry..)?.iter()
+            .position(|instr| matches!(instr, Instruction::Return))?;
+        if len > max_len {
+            return None;
+        }
+        let body = &self.program[entry..entry + len];
+        for (i, instr) in body.iter().enumerate() {
+            match instr {
+                Instruction::Jump(offset) | Instruction::IfZero(offset) => {
+                    let target = (i as isize) + offset;
+                    if target < 0 || target > len as isize {
+                        return None;
+                    }
+                }
+                Instruction::Call(_) | Instruction::RelCall(_) | Instruction::CallWord(_)
+                | Instruction::Halt => return None,
+                _ => {}
+            }
+        }
+        Some(body.to_vec())
+    }
+
+    /// Moves every stored address through an old-to-new index map produced
+    /// by a rewrite pass, including those of a run in progress, so a pass
+    /// may be applied between steps.
+    fn remap(&mut self, map: &[usize]) {
+        for addr in self.dictionary.values_mut() {
+            *addr = moved(map, *addr);
+        }
+        for addrs in self.history.values_mut() {
+            for addr in addrs.iter_mut() {
+                *addr = moved(map, *addr);
+            }
+        }
+        if let Some(covered) = &mut self.covered {
+            *covered = covered.iter().map(|&addr| moved(map, addr)).collect();
+        }
+        remap_run(map, &mut self.ip, &mut self.return_stack, &mut self.frames);
+        for undo in &mut self.undo_log {
+            remap_run(map, &mut undo.ip, &mut undo.return_stack, &mut undo.frames);
+        }
+    }
+
+    /// Calls the word at `addr`: saves the return address, opens a frame for
+    /// the per-call step budget and records coverage.
+    fn enter(&mut self, addr: usize) -> Result<(), VmError> {
+        if self.return_stack_limit.is_some_and(|limit| self.return_stack.len() >= limit) {
+            return Err(VmError::ReturnStackOverflow { word: self.word_name(addr) });
+        }
+        self.return_stack.push(self.ip + 1);
+        self.frames.push((addr, self.steps));
+        self.ip = addr;
+        if let Some(covered) = &mut self.covered {
+            covered.insert(addr);
+        }
+        Ok(())
+    }
+
+    /// The dictionary name for the entry address `addr`, or the address
+    /// itself when no current word starts there.
+    fn word_name(&self, addr: usize) -> String {
+        self.dictionary.iter()
+            .find(|(_, entry)| **entry == addr)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| format!("<{}>", addr))
+    }
+
+    /// Returns the names of user-defined words that are never called from the
+    /// main program or from any word reachable from it, in sorted order.
+    pub fn unused_words(&self) -> Vec<String> {
+        let mut called = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![0];
+
+        while let Some(ip) = pending.pop() {
+            if ip >= self.program.len() || !visited.insert(ip) {
+                continue;
+            }
+            if let Some(addr) = self.call_target(ip) {
+                called.insert(addr);
+                pending.push(addr);
+            }
+            pending.extend(self.successors(ip));
+        }
+
+        let mut unused: Vec<String> = self.dictionary.iter()
+            .filter(|(_, addr)| !called.contains(*addr))
+            .map(|(name, _)| name.clone())
+            .collect();
+        unused.sort();
+        unused
+    }
+
+    /// Indices of instructions that no path from the main program or from any
+    /// word's entry point can reach, such as code after an `exit`. Sorted.
+    pub fn unreachable_instructions(&self) -> Vec<usize> {
+        let mut visited = HashSet::new();
+        let mut pending: Vec<usize> = std::iter::once(0)
+            .chain(self.dictionary.values().copied())
+            .chain(self.history.values().flatten().copied())
+            .collect();
+
+        while let Some(ip) = pending.pop() {
+            if ip >= self.program.len() || !visited.insert(ip) {
+                continue;
+            }
+            pending.extend(self.call_target(ip));
+            pending.extend(self.successors(ip));
+        }
+
+        (0..self.program.len()).filter(|ip| !visited.contains(ip)).collect()
+    }
+
+    /// The address a call instruction at `ip` transfers to, if it is a call
+    /// to a known location.
+    fn call_target(&self, ip: usize) -> Option<usize> {
+        match &self.program[ip] {
+            Instruction::Call(addr) => Some(*addr),
+            Instruction::RelCall(offset) => Some(((ip as isize) + offset) as usize),
+            Instruction::CallWord(name) => self.dictionary.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Instructions that can execute directly after the one at `ip` within the
+    /// same word. Calls are treated as falling through once the callee returns.
+    fn successors(&self, ip: usize) -> Vec<usize> {
+        let offset_target = |offset: isize| ((ip as isize) + offset) as usize;
+        match &self.program[ip] {
+            Instruction::Jump(offset) => vec![offset_target(*offset)],
+            Instruction::IfZero(offset) => vec![offset_target(*offset), ip + 1],
+            Instruction::Return | Instruction::Halt => Vec::new(),
+            _ => vec![ip + 1],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    MissingWordName { line: usize },
+    UnexpectedSemicolon { line: usize },
+    /// A `:` while the definition of `name` was still open, usually a missing `;`.
+    NestedDefinition { name: String, line: usize },
+    MissingTestName { line: usize },
+    UnknownWord { name: String, line: usize },
+    /// `if` or `begin` without its `then` or `until` before the end of the
+    /// definition or input.
+    UnterminatedControl { word: &'static str, line: usize },
+    /// `test` without its `endtest` before the end of the definition or input.
+    UnterminatedTest { name: String, line: usize },
+    /// `else`, `then` or `until` with no matching opener.
+    UnmatchedControl { word: &'static str, line: usize },
+    /// `:` tried to define `:`, `;` or one of the parsing words.
+    ReservedWord { name: String, line: usize },
+    /// `forget` named a built-in, which can't be removed.
+    ForgetBuiltin { name: String, line: usize },
+    UnexpectedEndTest { line: usize },
+    /// `set_token` named something that isn't a built-in.
+    UnknownBuiltin { name: String },
+    /// `set_token` chose a spelling another built-in already has.
+    TokenInUse { token: String, builtin: &'static str },
+    /// `expect` outside a `test` block, or a second one in the same block.
+    UnexpectedExpect { line: usize },
+    /// `endtest` closed the test `name` before its `expect`.
+    MissingExpect { name: String, line: usize },
+    /// `allot` not directly preceded by a non-negative number literal.
+    MissingAllotCount { line: usize },
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingWordName { line } => write!(f, "Expected word name after ':' on line {}", line),
+            ParseError::UnexpectedSemicolon { line } => write!(f, "Unexpected ';' outside of word definition on line {}", line),
+            ParseError::NestedDefinition { name, line } => write!(f, "Nested ':' inside definition of '{}' on line {}", name, line),
+            ParseError::UnknownWord { name, line } => write!(f, "Unknown word '{}' on line {}", name, line),
+            ParseError::UnterminatedControl { word, line } => write!(f, "Unterminated '{}' opened on line {}", word, line),
+            ParseError::UnterminatedTest { name, line } => write!(f, "Test '{}' opened on line {} has no 'endtest'", name, line),
+            ParseError::UnmatchedControl { word, line } => write!(f, "Unmatched '{}' on line {}", word, line),
+            ParseError::ReservedWord { name, line } => write!(f, "Cannot redefine '{}' on line {}", name, line),
+            ParseError::ForgetBuiltin { name, line } => write!(f, "Cannot forget built-in '{}' on line {}", name, line),
+            ParseError::MissingTestName { line } => write!(f, "Expected test name after 'test' on line {}", line),
+            ParseError::UnexpectedEndTest { line } => write!(f, "Unexpected 'endtest' outside of test on line {}", line),
+            ParseError::UnknownBuiltin { name } => write!(f, "Unknown built-in '{}'", name),
+            ParseError::TokenInUse { token, builtin } => write!(f, "'{}' already spells the built-in '{}'", token, builtin),
+            ParseError::UnexpectedExpect { line } => write!(f, "Unexpected 'expect' outside of test on line {}", line),
+            ParseError::MissingExpect { name, line } => write!(f, "Test '{}' has no 'expect' before 'endtest' on line {}", name, line),
+            ParseError::MissingAllotCount { line } => write!(f, "Expected a cell count before 'allot' on line {}", line),
+            ParseError::Io(err) => write!(f, "Read error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+/// Something suspicious the parser accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// A user word named `name` now takes precedence over the built-in.
+    ShadowedBuiltin { name: String, line: usize },
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::ShadowedBuiltin { name, line } => write!(f, "'{}' on line {} shadows the built-in", name, line),
+        }
+    }
+}
+
+pub struct Parser {
+    main: Vec<Instruction>,
+    definitions: Vec<Instruction>,
+    dictionary: HashMap<String, usize>,
+    // Every definition of each name, oldest first, as offsets like `dictionary`
+    history: HashMap<String, Vec<usize>>,
+    // Kept across `parse` calls so a definition can span several inputs
+    defining: Option<String>,
+    buffer: Vec<Instruction>,
+    // Names of `test` blocks awaiting their `endtest`, whether each has
+    // seen its `expect`, and the line each opened on
+    pending_tests: Vec<(String, bool, usize)>,
+    // Source spelling of each built-in, keyed by token, valued by its name in `BUILTINS`
+    tokens: HashMap<String, &'static str>,
+    // Lines consumed by earlier `parse` calls, so line numbers keep counting
+    line: usize,
+    // Most user words the dictionary may hold; the least recently used are evicted
+    word_limit: Option<usize>,
+    // When each word was last defined or compiled into a call, for eviction
+    last_used: HashMap<String, usize>,
+    clock: usize,
+    // Words evicted by the word limit, by offset, so `forget` can unbind
+    // the calls that were bound to them
+    evicted: HashMap<usize, String>,
+    // Open control structures: kind, index of the branch or loop start in the
+    // current target, and the source line it opened on
+    control: Vec<(Control, usize, usize)>,
+    // Memory cells reserved so far by `variable` and `allot`
+    here: usize,
+    // `here` as each definition was made, by its offset, so `forget` can
+    // hand back the cells reserved after it
+    marks: Vec<(usize, usize)>,
+    // Collected until `take_warnings`
+    warnings: Vec<ParseWarning>,
+    // Open state as the latest `parse` call found it, restored by `abort`
+    checkpoint: Checkpoint,
+    // Opening line and words of a `( ... )` comment still waiting for its `)`
+    comment: Option<(usize, Vec<String>)>,
+    // Examples annotating the open definition, then by the offset of the
+    // definition they belong to, like `dictionary`
+    open_examples: Vec<Example>,
+    examples: HashMap<usize, Vec<Example>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Checkpoint {
+    main: usize,
+    defining: Option<String>,
+    buffer: Vec<Instruction>,
+    control: Vec<(Control, usize, usize)>,
+    pending_tests: Vec<(String, bool, usize)>,
+    comment: Option<(usize, Vec<String>)>,
+    open_examples: Vec<Example>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    If,
+    Else,
+    Begin,
+}
+
+impl Control {
+    /// The word that opened this construct, for error messages.
+    fn opener(self) -> &'static str {
+        match self {
+            Control::If | Control::Else => "if",
+            Control::Begin => "begin",
+        }
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            main: Vec::new(),
+            definitions: Vec::new(),
+            dictionary: HashMap::new(),
+            history: HashMap::new(),
+            defining: None,
+            buffer: Vec::new(),
+            pending_tests: Vec::new(),
+            tokens: BUILTINS.iter().map(|info| (info.name.to_string(), info.name)).collect(),
+            line: 0,
+            word_limit: None,
+            last_used: HashMap::new(),
+            clock: 0,
+            evicted: HashMap::new(),
+            control: Vec::new(),
+            here: 0,
+            marks: Vec::new(),
+            warnings: Vec::new(),
+            checkpoint: Checkpoint::default(),
+            comment: None,
+            open_examples: Vec::new(),
+            examples: HashMap::new(),
+        }
+    }
+
+    /// Caps the dictionary at `limit` user words. Defining one more evicts
+    /// the least recently used word, where a word is used when it is defined
+    /// or when later source compiles a call to it; calls made at run time
+    /// don't count, as eviction happens while parsing. The evicted word's
+    /// compiled code is left in place but can no longer be called by name;
+    /// definitions that already call it are bound to that code first, so
+    /// they keep working.
+    /// Built-ins are not dictionary entries and are never evicted.
+    pub fn with_word_limit(mut self, limit: usize) -> Self {
+        self.word_limit = Some(limit);
+        self
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some(limit) = self.word_limit else { return };
+        while self.dictionary.len() > limit {
+            let oldest = self.dictionary.keys()
+                .min_by_key(|name| self.last_used.get(*name))
+                .cloned()
+                .unwrap();
+            self.bind_calls(&oldest);
+            self.evicted.insert(self.dictionary[&oldest], oldest.clone());
+            self.dictionary.remove(&oldest);
+            self.history.remove(&oldest);
+            self.last_used.remove(&oldest);
+        }
+    }
+
+    /// Turns every call to `name` in the definitions into a `RelCall` to the
+    /// code `name` refers to now.
+    fn bind_calls(&mut self, name: &str) {
+        let target = self.dictionary[name];
+        for (at, instr) in self.definitions.iter_mut().enumerate() {
+            if matches!(instr, Instruction::CallWord(called) if called == name) {
+                *instr = Instruction::RelCall(target as isize - at as isize);
+            }
+        }
+    }
+
+    /// Marks `name` as the most recently used word.
+    fn touch(&mut self, name: &str) {
+        self.clock += 1;
+        self.last_used.insert(name.to_string(), self.clock);
+    }
+
+    /// Name of the word currently being defined, if a `:` is still open.
+    pub fn defining(&self) -> Option<&str> {
+        self.defining.as_deref()
+    }
+
+    /// Forth STATE: `0` while interpreting, `-1` while compiling a definition.
+    pub fn state(&self) -> i32 {
+        if self.defining.is_some() { -1 } else { 0 }
+    }
+
+    /// Warnings from the input parsed since the last call.
+    pub fn take_warnings(&mut self) -> Vec<ParseWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Instructions compiled so far for the open definition.
+    pub fn pending(&self) -> &[Instruction] {
+        &self.buffer
+    }
+
+    /// Makes `token` the spelling of the built-in word `builtin` (its default
+    /// name from `BUILTINS`), replacing whatever spelling it had before. The
+    /// old spelling becomes an ordinary word. Fails with `UnknownBuiltin` if
+    /// `builtin` isn't one, and with `TokenInUse` if `token` spells a
+    /// different built-in.
+    pub fn set_token(&mut self, builtin: &str, token: &str) -> Result<(), ParseError> {
+        let info = builtin_info(builtin)
+            .ok_or_else(|| ParseError::UnknownBuiltin { name: builtin.to_string() })?;
+        if let Some(&other) = self.tokens.get(token)
+            && other != info.name
+        {
+            return Err(ParseError::TokenInUse { token: token.to_string(), builtin: other });
+        }
+        self.tokens.retain(|_, name| *name != info.name);
+        self.tokens.insert(token.to_string(), info.name);
+        Ok(())
+    }
+
+    /// Parses `input`. A definition may stay open for a later call, but
+    /// control structures and tests outside definitions must be closed
+    /// within it.
+    pub fn parse(&mut self, input: &str) -> Result<(), ParseError> {
+        self.save_checkpoint();
+        self.parse_chunk(input)?;
+        self.check_control_closed()
+    }
+
+    fn parse_chunk(&mut self, input: &str) -> Result<(), ParseError> {
+        let base = self.line;
+        self.line += input.lines().count();
+        let mut tokens = tokenize(input).into_iter()
+            .map(|(token, line)| (token, base + line));
+
+        while let Some((token, line)) = tokens.next() {
+            // `( ... )` comments may span lines and `parse` calls
+            if let Some((_, words)) = &mut self.comment {
+                match token.strip_suffix(')') {
+                    Some(last) => {
+                        if !last.is_empty() {
+                            words.push(last.to_string());
+                        }
+                        self.close_comment();
+                    }
+                    None => words.push(token.to_string()),
+                }
+                continue;
+            }
+            if token == "(" {
+                self.comment = Some((line, Vec::new()));
+                continue;
+            }
+            if let Some(n) = parse_number(token) {
+                self.emit(Instruction::Push(n));
+                continue;
+            }
+            // A user definition shadows a built-in instruction word of the
+            // same name. It only becomes visible at its `;`, so a body can
+            // still use the built-in it replaces, e.g. `: dup dup . ;`.
+            let builtin = self.tokens.get(token).copied()
+                .filter(|_| !self.dictionary.contains_key(token));
+            match builtin {
+                Some("test") => {
+                    let (name, _) = tokens.next().ok_or(ParseError::MissingTestName { line })?;
+                    self.pending_tests.push((name.to_string(), false, line));
+                    self.emit(Instruction::TestBegin);
+                }
+                Some("expect") => {
+                    match self.pending_tests.last_mut() {
+                        Some((_, seen @ false, _)) => *seen = true,
+                        _ => return Err(ParseError::UnexpectedExpect { line }),
+                    }
+                    self.emit(Instruction::TestExpect);
+                }
+                Some("forget") => {
+                    let (name, _) = tokens.next().ok_or(ParseError::MissingWordName { line })?;
+                    self.forget(name, line)?;
+                }
+                Some("endtest") => {
+                    let (name, seen, _) = self.pending_tests.pop().ok_or(ParseError::UnexpectedEndTest { line })?;
+                    if !seen {
+                        return Err(ParseError::MissingExpect { name, line });
+                    }
+                    self.emit(Instruction::TestEnd(name));
+                }
+                Some("variable") => {
+                    let (name, _) = tokens.next().ok_or(ParseError::MissingWordName { line })?;
+                    self.check_name(name, line)?;
+                    let addr = self.here as i32;
+                    self.define(name.to_string(), vec![Instruction::Address(addr), Instruction::Return]);
+                    self.here += 1;
+                }
+                Some("allot") => {
+                    // The count is taken at parse time, so it has to be a literal
+                    match self.target().pop() {
+                        Some(Instruction::Push(n)) if n >= 0 => self.here += n as usize,
+                        other => {
+                            self.target().extend(other);
+                            return Err(ParseError::MissingAllotCount { line });
+                        }
+                    }
+                }
+                Some(word @ ("if" | "else" | "then" | "begin" | "until")) => self.control(word, line)?,
+                Some(builtin) => self.emit(builtin_instruction(builtin)),
+                None => match token {
+                    ":" => {
+                        if let Some(name) = &self.defining {
+                            return Err(ParseError::NestedDefinition { name: name.clone(), line });
+                        }
+                        self.check_control_closed()?;
+                        let (name, _) = tokens.next().ok_or(ParseError::MissingWordName { line })?;
+                        self.check_name(name, line)?;
+                        self.defining = Some(name.to_string());
+                        self.buffer.clear();
+                        self.open_examples.clear();
+                    }
+                    ";" => {
+                        if let Some(name) = self.defining.take() {
+                            if let Err(err) = self.check_control_closed() {
+                                self.defining = Some(name);
+                                return Err(err);
+                            }
+                            let mut body = std::mem::take(&mut self.buffer);
+                            body.push(Instruction::Return);
+                            let examples = std::mem::take(&mut self.open_examples);
+                            self.examples.insert(self.definitions.len(), examples);
+                            self.define(name, body);
+                        } else {
+                            return Err(ParseError::UnexpectedSemicolon { line });
+                        }
+                    }
+                    word => {
+                        if self.dictionary.contains_key(word) {
+                            self.touch(word);
+                        }
+                        self.emit(Instruction::CallWord(word.to_string()));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses source from `reader` one line at a time, so the whole input
+    /// never has to be held in memory. Line numbers continue as for `parse`.
+    pub fn parse_reader(&mut self, mut reader: impl BufRead) -> Result<(), ParseError> {
+        self.save_checkpoint();
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            self.parse_chunk(&line)?;
+            line.clear();
+        }
+        self.check_control_closed()
+    }
+
+    /// Ends the open `( ... )` comment. Inside a definition, a comment made
+    /// only of numbers either side of `--` is kept as an example.
+    fn close_comment(&mut self) {
+        let Some((line, words)) = self.comment.take() else { return };
+        let Some(word) = self.defining.clone() else { return };
+        if let Some((input, output)) = parse_example(&words) {
+            self.open_examples.push(Example { word, input, output, line });
+        }
+    }
+
+    fn save_checkpoint(&mut self) {
+        self.checkpoint = Checkpoint {
+            main: self.main.len(),
+            defining: self.defining.clone(),
+            buffer: self.buffer.clone(),
+            control: self.control.clone(),
+            pending_tests: self.pending_tests.clone(),
+            comment: self.comment.clone(),
+            open_examples: self.open_examples.clone(),
+        };
+    }
+
+    /// Appends `body` to the definitions and makes `name` refer to it.
+    fn define(&mut self, name: String, mut body: Vec<Instruction>) {
+        // Offset into `definitions`; rebased past main and HALT in link
+        self.marks.push((self.definitions.len(), self.here));
+        self.history.entry(name.clone()).or_default().push(self.definitions.len());
+        self.touch(&name);
+        self.dictionary.insert(name, self.definitions.len());
+        self.definitions.append(&mut body);
+        self.evict_oldest();
+    }
+
+    /// Rejects a new word called `name` if it is reserved, and warns if it
+    /// shadows a built-in.
+    fn check_name(&mut self, name: &str, line: usize) -> Result<(), ParseError> {
+        if self.is_reserved(name) {
+            return Err(ParseError::ReservedWord { name: name.to_string(), line });
+        }
+        if self.tokens.contains_key(name) {
+            self.warnings.push(ParseWarning::ShadowedBuiltin { name: name.to_string(), line });
+        }
+        Ok(())
+    }
+
+    /// Words that can't be redefined: `:`, `;` and `(`, and the parsing
+    /// words, which act at parse time and so couldn't be replaced by a call.
+    fn is_reserved(&self, name: &str) -> bool {
+        name == ":" || name == ";" || name == "("
+            || self.tokens.get(name).is_some_and(|builtin| PARSING_WORDS.contains(builtin))
+    }
+
+    /// Compiles a control-flow word. Forward branches are emitted with a
+    /// placeholder offset and patched once their target is known; `control`
+    /// holds the open constructs.
+    fn control(&mut self, word: &'static str, line: usize) -> Result<(), ParseError> {
+        let here = self.target().len();
+        match word {
+            "if" => {
+                self.emit(Instruction::IfZero(0));
+                self.control.push((Control::If, here, line));
+            }
+            "else" => {
+                let (_, at, _) = self.pop_control(&[Control::If], word, line)?;
+                self.emit(Instruction::Jump(0));
+                self.patch(at, here + 1);
+                self.control.push((Control::Else, here, line));
+            }
+            "then" => {
+                let (_, at, _) = self.pop_control(&[Control::If, Control::Else], word, line)?;
+                self.patch(at, here);
+            }
+            "begin" => self.control.push((Control::Begin, here, line)),
+            "until" => {
+                let (_, start, _) = self.pop_control(&[Control::Begin], word, line)?;
+                self.emit(Instruction::IfZero(start as isize - here as isize));
+            }
+            _ => unreachable!("not a control word: {}", word),
+        }
+        Ok(())
+    }
+
+    fn pop_control(&mut self, expected: &[Control], word: &'static str, line: usize) -> Result<(Control, usize, usize), ParseError> {
+        match self.control.last() {
+            Some((kind, _, _)) if expected.contains(kind) => Ok(self.control.pop().unwrap()),
+            _ => Err(ParseError::UnmatchedControl { word, line }),
+        }
+    }
+
+    /// Points the branch at index `at` of the current target to `dest`.
+    fn patch(&mut self, at: usize, dest: usize) {
+        let offset = dest as isize - at as isize;
+        match &mut self.target()[at] {
+            Instruction::IfZero(o) | Instruction::Jump(o) => *o = offset,
+            other => unreachable!("patching a non-branch: {:?}", other),
+        }
+    }
+
+    /// Fails if an `if`, `begin` or `test` is still waiting for its closing
+    /// word. Inside an open definition they may stay open, as the rest of the
+    /// definition can arrive with a later `parse`.
+    fn check_control_closed(&self) -> Result<(), ParseError> {
+        if self.defining.is_some() {
+            return Ok(());
+        }
+        if let Some((kind, _, line)) = self.control.last() {
+            return Err(ParseError::UnterminatedControl { word: kind.opener(), line: *line });
+        }
+        match self.pending_tests.last() {
+            Some((name, _, line)) => Err(ParseError::UnterminatedTest { name: name.clone(), line: *line }),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes `name` and every definition made after it, truncating the
+    /// compiled definitions. Names that were redefined after `name` fall back
+    /// to their last surviving definition. Data space reserved by `variable`
+    /// and `allot` since `name` was defined is handed back too; a VM kept
+    /// across `relink`s reuses those cells without clearing them.
+    fn forget(&mut self, name: &str, line: usize) -> Result<(), ParseError> {
+        if self.tokens.contains_key(name) && !self.dictionary.contains_key(name) {
+            return Err(ParseError::ForgetBuiltin { name: name.to_string(), line });
+        }
+        let offset = *self.dictionary.get(name)
+            .ok_or_else(|| ParseError::UnknownWord { name: name.to_string(), line })?;
+        self.definitions.truncate(offset);
+        // Calls bound to an evicted word that is now gone go back to calling by name
+        for (at, instr) in self.definitions.iter_mut().enumerate() {
+            if let Instruction::RelCall(rel) = *instr
+                && let Some(name) = self.evicted.get(&((at as isize + rel) as usize))
+                && (at as isize + rel) as usize >= offset
+            {
+                *instr = Instruction::CallWord(name.clone());
+            }
+        }
+        self.evicted.retain(|&o, _| o < offset);
+        if let Some(&(_, here)) = self.marks.iter().find(|(o, _)| *o == offset) {
+            self.here = here;
+        }
+        self.marks.retain(|(o, _)| *o < offset);
+        self.examples.retain(|&o, _| o < offset);
+        for offsets in self.history.values_mut() {
+            offsets.retain(|&o| o < offset);
+        }
+        self.history.retain(|_, offsets| !offsets.is_empty());
+        self.dictionary = self.history.iter()
+            .map(|(name, offsets)| (name.clone(), *offsets.last().unwrap()))
+            .collect();
+        self.last_used.retain(|name, _| self.dictionary.contains_key(name));
+        Ok(())
+    }
+
+    /// Appends to the open definition if there is one, else to main.
+    fn emit(&mut self, instr: Instruction) {
+        self.target().push(instr);
+    }
+
+    /// The open definition if there is one, else main.
+    fn target(&mut self) -> &mut Vec<Instruction> {
+        if self.defining.is_some() { &mut self.buffer } else { &mut self.main }
+    }
+
+    pub fn finalize(self) -> (Vec<Instruction>, HashMap<String, usize>) {
+        let vm = self.link();
+        (vm.program, vm.dictionary)
+    }
+
+    /// Lays out main, HALT and the definitions into a VM, with the dictionary
+    /// and definition history rebased to absolute addresses.
+    fn link(&self) -> VM {
+        let base = self.main.len() + 1; // +1 for HALT
+        let mut instructions = self.main.clone();
+        instructions.push(Instruction::Halt); // ✅ main program ends here
+        instructions.extend(self.definitions.iter().cloned());
+        let dictionary: HashMap<String, usize> = self.dictionary.iter()
+            .map(|(name, offset)| (name.clone(), base + offset))
+            .collect();
+        let history = self.history.iter()
+            .map(|(name, offsets)| (name.clone(), offsets.iter().map(|offset| base + offset).collect()))
+            .collect();
+        let (instructions, map) = coalesce_pushes(instructions, &dictionary);
+        let mut vm = VM::new(instructions);
+        vm.dictionary = dictionary;
+        vm.history = history;
+        vm.memory = vec![0; self.here];
+        vm.compiling = self.defining.is_some();
+        // Only examples of the definitions each name currently refers to
+        vm.examples = self.examples.iter()
+            .flat_map(|(offset, examples)| examples.iter().filter(|example| self.dictionary.get(&example.word) == Some(offset)))
+            .cloned()
+            .collect();
+        vm.examples.sort_by_key(|example| example.line);
+        vm.remap(&map);
+        vm
+    }
+
+    /// Loads the words defined so far, plus the top-level code parsed since
+    /// the last `relink`, into `vm` and rewinds it to run that code. The data
+    /// stack and memory are kept, so feeding one parser line by line and
+    /// relinking after each gives a REPL whose words persist. Addresses may
+    /// move between relinks; `vm`'s dictionary and history are updated. The
+    /// step count starts again from zero, so a step limit applies per line.
+    pub fn relink<W: Write>(&mut self, vm: &mut VM<W>) {
+        let linked = self.link();
+        self.main.clear();
+        vm.program = linked.program;
+        vm.dictionary = linked.dictionary;
+        vm.history = linked.history;
+        vm.compiling = linked.compiling;
+        vm.examples = linked.examples;
+        vm.ip = 0;
+        vm.steps = 0;
+        vm.return_stack.clear();
+        vm.frames.clear();
+        vm.test_marks.clear();
+        vm.undo_log.clear();
+        if vm.memory.len() < linked.memory.len() {
+            vm.memory.resize(linked.memory.len(), 0);
+        }
+    }
+
+    /// Undoes what a failed `parse` added to the top-level code and to any
+    /// open definition, control structure or test. Whatever was open before
+    /// that call is restored, so a REPL can carry on with a definition typed
+    /// over several lines. Words the failed input completed are kept.
+    pub fn abort(&mut self) {
+        let checkpoint = std::mem::take(&mut self.checkpoint);
+        self.main.truncate(checkpoint.main);
+        self.defining = checkpoint.defining;
+        self.buffer = checkpoint.buffer;
+        self.control = checkpoint.control;
+        self.pending_tests = checkpoint.pending_tests;
+        self.comment = checkpoint.comment;
+        self.open_examples = checkpoint.open_examples;
+    }
+
+    /// Like `finalize`, but resolves calls to known words into `RelCall`
+    /// offsets so the resulting code can be relocated. Calls to words that
+    /// are not defined are left as `CallWord`.
+    pub fn finalize_relative(self) -> (Vec<Instruction>, HashMap<String, usize>) {
+        let (mut instructions, dictionary) = self.finalize();
+        for (ip, instr) in instructions.iter_mut().enumerate() {
+            if let Instruction::CallWord(name) = instr
+                && let Some(&addr) = dictionary.get(name)
+            {
+                *instr = Instruction::RelCall(addr as isize - ip as isize);
+            }
+        }
+        (instructions, dictionary)
+    }
+}
+
+/// Parses a decimal literal, or a hex literal with a `0x`/`0X` prefix. Either
+/// may have a leading `-`.
+fn parse_number(token: &str) -> Option<i32> {
+    if let Ok(n) = token.parse::<i32>() {
+        return Some(n);
+    }
+    let (negative, rest) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let digits = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X"))?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let magnitude = i64::from_str_radix(digits, 16).ok()?;
+    i32::try_from(if negative { -magnitude } else { magnitude }).ok()
+}
+
+/// The values either side of `--` in a comment like `( 3 4 -- 7 )`, if all
+/// the other words are numbers.
+fn parse_example(words: &[String]) -> Option<(Vec<i32>, Vec<i32>)> {
+    let split = words.iter().position(|word| word == "--")?;
+    let numbers = |part: &[String]| part.iter().map(|word| parse_number(word)).collect::<Option<Vec<_>>>();
+    let input = numbers(&words[..split])?;
+    let output = numbers(&words[split + 1..])?;
+    (!input.is_empty() || !output.is_empty()).then_some((input, output))
+}
+
+/// Splits `input` into whitespace-separated tokens, each paired with its
+/// 1-based line number. A `\` token comments out the rest of its line.
+pub fn tokenize(input: &str) -> Vec<(&str, usize)> {
+    input.lines()
+        .enumerate()
+        .flat_map(|(i, line)| {
+            line.split_whitespace()
+                .take_while(|token| *token != "\\")
+                .map(move |token| (token, i + 1))
+        })
+        .collect()
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An interactive session. Each line is parsed and run against the same
+/// parser and VM, so words and the stack persist between lines. A line that
+/// fails to parse or run is reported and the session carries on; one that
+/// fails part way through leaves the stack and memory as they were before
+/// it, so it can be fixed and entered again. Everything
+/// the session prints, errors included, goes to the VM's output sink.
+pub struct Repl<W = std::io::Stdout> {
+    parser: Parser,
+    vm: VM<W>,
+    /// Whether to print the values each line leaves on the stack.
+    echo_results: bool,
+    /// Lines entered so far, oldest first, at most `history_limit` of them.
+    history: VecDeque<String>,
+    history_limit: usize,
+    /// Index into `history` while stepping back through it.
+    cursor: Option<usize>,
+    /// Printed before reading each line.
+    prompt: String,
+    /// Printed after the stack when a line succeeds, with `{depth}`
+    /// replaced by the stack depth.
+    banner: String,
+}
+
+/// How many lines a `Repl` remembers unless changed with
+/// `Repl::with_history_limit`.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            vm: VM::new(Vec::new()),
+            echo_results: false,
+            history: VecDeque::new(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            cursor: None,
+            prompt: "> ".to_string(),
+            banner: "ok".to_string(),
+        }
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> Repl<W> {
+    /// Sends the session's output to `out` instead of stdout.
+    pub fn with_output<X: Write>(self, out: X) -> Repl<X> {
+        Repl {
+            parser: self.parser,
+            vm: self.vm.with_output(out),
+            echo_results: self.echo_results,
+            history: self.history,
+            history_limit: self.history_limit,
+            cursor: self.cursor,
+            prompt: self.prompt,
+            banner: self.banner,
+        }
+    }
+
+    /// Prompts with `prompt` instead of `> `.
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Follows the stack after a successful line with `banner` instead of
+    /// `ok`. A `{depth}` in it is replaced by the stack depth, so `ok
+    /// {depth}` gives `ok 2`; an empty banner prints nothing.
+    pub fn with_banner(mut self, banner: impl Into<String>) -> Self {
+        self.banner = banner.into();
+        self
+    }
+
+    /// Keeps only the last `limit` lines in the history, replacing the
+    /// default of `DEFAULT_HISTORY_LIMIT`.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+        self.cursor = None;
+        self
+    }
+
+    /// The lines entered so far, oldest first. Blank lines aren't recorded.
+    pub fn history(&self) -> &VecDeque<String> {
+        &self.history
+    }
+
+    /// Steps back through the history, as the up arrow does: the newest line
+    /// first, then older ones, stopping at the oldest. Reading keys is left
+    /// to the caller; `run` itself reads plain lines.
+    pub fn history_previous(&mut self) -> Option<&str> {
+        let cursor = match self.cursor {
+            Some(cursor) => cursor.saturating_sub(1),
+            None => self.history.len().checked_sub(1)?,
+        };
+        self.cursor = Some(cursor);
+        self.history.get(cursor).map(String::as_str)
+    }
+
+    /// Steps forward again, as the down arrow does. Returns `None` once past
+    /// the newest line, back at an empty input.
+    pub fn history_next(&mut self) -> Option<&str> {
+        let cursor = self.cursor? + 1;
+        self.cursor = (cursor < self.history.len()).then_some(cursor);
+        self.history.get(cursor).map(String::as_str)
+    }
+
+    /// Prints the values each line adds to the stack, calculator style,
+    /// before the stack itself. Values below the deepest point the line
+    /// changed aren't repeated.
+    pub fn with_echo_results(mut self) -> Self {
+        self.echo_results = true;
+        self
+    }
+
+    /// The VM the session runs lines on.
+    pub fn vm(&self) -> &VM<W> {
+        &self.vm
+    }
+
+    /// Consumes the session and returns its output sink.
+    pub fn into_output(self) -> W {
+        self.vm.out
+    }
+
+    /// Reads lines from `input` until EOF or `.quit`, prompting before each
+    /// and evaluating it.
+    pub fn run(&mut self, mut input: impl BufRead) -> std::io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(self.vm.out, "{}", self.prompt)?;
+            self.vm.out.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(self.vm.out)?;
+                break;
+            }
+            if line.trim() == ".quit" {
+                break;
+            }
+            self.eval(&line)?;
+        }
+        self.vm.out.flush()
+    }
+
+    /// Parses and runs one line, then prints the stack, followed by the
+    /// banner if the line ran without error. Parse and run errors are
+    /// printed rather than returned; only failing to write is an error.
+    pub fn eval(&mut self, line: &str) -> std::io::Result<()> {
+        self.record(line);
+        if let Err(err) = self.parser.parse(line) {
+            writeln!(self.vm.out, "{}", err)?;
+            self.parser.abort();
+            return Ok(());
+        }
+        for warning in self.parser.take_warnings() {
+            writeln!(self.vm.out, "warning: {}", warning)?;
+        }
+        let stack = self.vm.stack.clone();
+        let memory = self.vm.memory.clone();
+        self.parser.relink(&mut self.vm);
+        let result = self.vm.run();
+        if let Err(err) = &result {
+            // Cells reserved by the line stay, but start from zero again
+            let cells = self.vm.memory.len();
+            self.vm.stack = stack.clone();
+            self.vm.memory = memory;
+            self.vm.memory.resize(cells, 0);
+            writeln!(self.vm.out, "{}", err)?;
+        }
+        if self.echo_results {
+            let kept = stack.iter().zip(&self.vm.stack).take_while(|(old, new)| old == new).count();
+            let added = &self.vm.stack[kept..];
+            if !added.is_empty() {
+                let text = added.iter().map(i32::to_string).collect::<Vec<_>>().join(" ");
+                writeln!(self.vm.out, "{}", text)?;
+            }
+        }
+        match result {
+            Ok(()) if !self.banner.is_empty() => {
+                let banner = self.banner.replace("{depth}", &self.vm.stack.len().to_string());
+                writeln!(self.vm.out, "{:?} {}", self.vm.stack, banner)
+            }
+            _ => writeln!(self.vm.out, "{:?}", self.vm.stack),
+        }
+    }
+
+    /// Adds `line` to the history, dropping the oldest line when full, and
+    /// resets history navigation.
+    fn record(&mut self, line: &str) {
+        self.cursor = None;
+        let line = line.trim();
+        if line.is_empty() || self.history_limit == 0 {
+            return;
+        }
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.to_string());
+    }
+}
+
+/// A numbered listing of `program`, one instruction per line. Each word's
+/// entry is preceded by a `name:` label, and calls and branches show the
+/// absolute address they go to, plus the word starting there if any.
+pub fn disassemble(program: &[Instruction], dictionary: &HashMap<String, usize>) -> String {
+    let mut names: HashMap<usize, Vec<&str>> = HashMap::new();
+    for (name, addr) in dictionary {
+        names.entry(*addr).or_default().push(name);
+    }
+    for list in names.values_mut() {
+        list.sort();
+    }
+    let target = |addr: usize| match names.get(&addr) {
+        Some(list) => format!("{} ({})", addr, list.join(", ")),
+        None => addr.to_string(),
+    };
+
+    let mut out = String::new();
+    for (ip, instr) in program.iter().enumerate() {
+        if let Some(list) = names.get(&ip) {
+            for name in list {
+                out.push_str(&format!("{}:\n", name));
+            }
+        }
+        let relative = |offset: &isize| ((ip as isize) + offset) as usize;
+        let text = match instr {
+            Instruction::Push(value) => format!("PUSH {}", value),
+            Instruction::Address(addr) => format!("ADDRESS {}", addr),
+            Instruction::PushN(values) => {
+                let values: Vec<String> = values.iter().map(i32::to_string).collect();
+                format!("PUSHN {}", values.join(" "))
+            }
+            Instruction::Add => "ADD".to_string(),
+            Instruction::Sub => "SUB".to_string(),
+            Instruction::Mul => "MUL".to_string(),
+            Instruction::Div => "DIV".to_string(),
+            Instruction::Mod => "MOD".to_string(),
+            Instruction::Eq => "EQ".to_string(),
+            Instruction::Lt => "LT".to_string(),
+            Instruction::Gt => "GT".to_string(),
+            Instruction::And => "AND".to_string(),
+            Instruction::Or => "OR".to_string(),
+            Instruction::Not => "NOT".to_string(),
+            Instruction::Dup => "DUP".to_string(),
+            Instruction::Drop => "DROP".to_string(),
+            Instruction::Swap => "SWAP".to_string(),
+            Instruction::Over => "OVER".to_string(),
+            Instruction::Rot => "ROT".to_string(),
+            Instruction::Nip => "NIP".to_string(),
+            Instruction::Tuck => "TUCK".to_string(),
+            Instruction::TwoDup => "2DUP".to_string(),
+            Instruction::TwoDrop => "2DROP".to_string(),
+            Instruction::TwoSwap => "2SWAP".to_string(),
+            Instruction::Depth => "DEPTH".to_string(),
+            Instruction::State => "STATE".to_string(),
+            Instruction::Abs => "ABS".to_string(),
+            Instruction::FmMod => "FM/MOD".to_string(),
+            Instruction::SmRem => "SM/REM".to_string(),
+            Instruction::Iota => "IOTA".to_string(),
+            Instruction::Dot => "DOT".to_string(),
+            Instruction::Emit => "EMIT".to_string(),
+            Instruction::Fetch => "FETCH".to_string(),
+            Instruction::Store => "STORE".to_string(),
+            Instruction::TestBegin => "TESTBEGIN".to_string(),
+            Instruction::TestExpect => "TESTEXPECT".to_string(),
+            Instruction::TestEnd(name) => format!("TESTEND {}", name),
+            Instruction::Jump(offset) => format!("JUMP {:+} -> {}", offset, target(relative(offset))),
+            Instruction::IfZero(offset) => format!("IFZERO {:+} -> {}", offset, target(relative(offset))),
+            Instruction::Call(addr) => format!("CALL {}", target(*addr)),
+            Instruction::RelCall(offset) => format!("RELCALL {:+} -> {}", offset, target(relative(offset))),
+            Instruction::CallWord(name) => match dictionary.get(name) {
+                Some(addr) => format!("CALLWORD {} -> {}", name, addr),
+                None => format!("CALLWORD {} -> ?", name),
+            },
+            Instruction::Return => "RETURN".to_string(),
+            Instruction::Halt => "HALT".to_string(),
+        };
+        out.push_str(&format!("{:4}  {}\n", ip, text));
+    }
+    out
+}
+
+/// A unit of source as the formatter sees it, with the lines it starts and
+/// ends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece<'a> {
+    Word(&'a str),
+    /// A `( ... )` comment, with its spacing normalized and its line breaks
+    /// kept.
+    Comment(String),
+    /// A `\` comment, running to the end of its line.
+    LineComment(&'a str),
+}
+
+/// Splits `source` into words and comments, like the parser but keeping the
+/// comments. As in the parser, a `\` ends its line even inside a `( ... )`
+/// comment, which then carries on on the next line.
+fn pieces(source: &str) -> Vec<(Piece<'_>, usize, usize)> {
+    let mut pieces = Vec::new();
+    // Opening line and text so far, one entry per source line, of a
+    // `( ... )` comment still waiting for its `)`
+    let mut comment: Option<(usize, Vec<String>)> = None;
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        if let Some((_, lines)) = &mut comment {
+            lines.push(String::new());
+        }
+        for token in text.split_whitespace() {
+            if token == "\\" {
+                let start = token.as_ptr() as usize - text.as_ptr() as usize;
+                let rest = text[start..].trim_end();
+                match &mut comment {
+                    Some((_, lines)) => push_word(lines.last_mut().unwrap(), rest),
+                    None => pieces.push((Piece::LineComment(rest), line, line)),
+                }
+                break;
+            }
+            if let Some((start, lines)) = &mut comment {
+                let current = lines.last_mut().unwrap();
+                match token.strip_suffix(')') {
+                    Some(last) => {
+                        push_word(current, last);
+                        push_word(current, ")");
+                        pieces.push((Piece::Comment(lines.join("\n")), *start, line));
+                        comment = None;
+                    }
+                    None => push_word(current, token),
+                }
+            } else if token == "(" {
+                comment = Some((line, vec!["(".to_string()]));
+            } else {
+                pieces.push((Piece::Word(token), line, line));
+            }
+        }
+    }
+    // An unterminated comment is kept as it was
+    if let Some((start, lines)) = comment {
+        pieces.push((Piece::Comment(lines.join("\n")), start, source.lines().count()));
+    }
+    pieces
+}
+
+/// Appends `word` to a line of comment text, space-separated.
+fn push_word(line: &mut String, word: &str) {
+    if word.is_empty() {
+        return;
+    }
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push_str(word);
+}
+
+impl Piece<'_> {
+    fn text(&self) -> &str {
+        match self {
+            Piece::Word(word) | Piece::LineComment(word) => word,
+            Piece::Comment(text) => text,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Piece::Word("if" | "else" | "then" | "begin" | "until"))
+    }
+}
+
+/// Lays out one definition, from its `:` up to and including its `;`. One
+/// without control structures or `\` comments stays on a single line; any
+/// other puts its name and leading comments on the first line and indents
+/// the body two spaces per level of `if` and `begin`.
+fn format_definition(pieces: &[Piece], lines: &mut Vec<String>) {
+    let join = |pieces: &[&Piece]| pieces.iter().map(|piece| piece.text()).collect::<Vec<_>>().join(" ");
+    if !pieces.iter().any(|piece| piece.is_control() || matches!(piece, Piece::LineComment(_))) {
+        lines.push(join(&pieces.iter().collect::<Vec<_>>()));
+        return;
+    }
+
+    let name = pieces.len().min(2);
+    let header = name + pieces[name..].iter().take_while(|piece| matches!(piece, Piece::Comment(_))).count();
+    lines.push(join(&pieces[..header].iter().collect::<Vec<_>>()));
+    let mut depth = 1;
+    let mut current: Vec<&Piece> = Vec::new();
+    let flush = |current: &mut Vec<&Piece>, depth: usize, lines: &mut Vec<String>| {
+        if !current.is_empty() {
+            // Later lines of a multi-line comment get the same indent
+            let indent = "  ".repeat(depth);
+            lines.push(format!("{}{}", indent, join(current).replace('\n', &format!("\n{}", indent))));
+            current.clear();
+        }
+    };
+    for piece in &pieces[header..] {
+        match piece {
+            Piece::Word("if" | "begin") => {
+                current.push(piece);
+                flush(&mut current, depth, lines);
+                depth += 1;
+            }
+            Piece::Word("else") => {
+                flush(&mut current, depth, lines);
+                current.push(piece);
+                flush(&mut current, depth.saturating_sub(1), lines);
+            }
+            Piece::Word("then" | "until") => {
+                flush(&mut current, depth, lines);
+                depth = depth.saturating_sub(1);
+                current.push(piece);
+            }
+            Piece::LineComment(_) => {
+                current.push(piece);
+                flush(&mut current, depth, lines);
+            }
+            _ => current.push(piece),
+        }
+    }
+    flush(&mut current, depth, lines);
+}
+
+/// Re-lays out Forth `source` in a canonical style, keeping its comments.
+/// Each definition starts on a line of its own and is laid out as
+/// `format_definition` describes. Other code keeps its line breaks, with
+/// runs of blank lines squeezed to one and spacing normalized. Formatting
+/// formatted source leaves it unchanged.
+pub fn format_source(source: &str) -> String {
+    let pieces = pieces(source);
+    let mut lines: Vec<String> = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    // Source line the last piece laid out ended on
+    let mut last = None;
+    let mut i = 0;
+    while let Some((piece, start, end)) = pieces.get(i) {
+        let definition = *piece == Piece::Word(":");
+        if (definition || last.is_none_or(|last| *start > last)) && !current.is_empty() {
+            lines.push(current.join(" "));
+            current.clear();
+        }
+        if last.is_some_and(|last| *start > last + 1) {
+            lines.push(String::new());
+        }
+
+        if definition {
+            // Lines of the definition go straight to `lines`, so code
+            // following its `;` starts a new line
+            let stop = pieces[i..].iter()
+                .position(|(piece, _, _)| *piece == Piece::Word(";"))
+                .map_or(pieces.len(), |offset| i + offset + 1);
+            let body: Vec<Piece> = pieces[i..stop].iter().map(|(piece, _, _)| piece.clone()).collect();
+            format_definition(&body, &mut lines);
+            last = Some(pieces[stop - 1].2);
+            i = stop;
+            continue;
+        }
+
+        current.push(piece.text());
+        if matches!(piece, Piece::LineComment(_)) {
+            lines.push(current.join(" "));
+            current.clear();
+        }
+        last = Some(*end);
+        i += 1;
+    }
+    if !current.is_empty() {
+        lines.push(current.join(" "));
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// An instruction emitted by a rewrite pass. `origin` is the index of the
+/// original instruction it was carried over from, in which case its targets
+/// are repaired; freshly built instructions (`None`) are emitted untouched.
+type Rewritten = (Instruction, Option<usize>);
+
+/// Rebuilds `program` by handing each instruction to `emit`, which pushes its
+/// replacement(s) onto the output (or merges into the last one). Absolute and
+/// relative targets of carried-over instructions are then fixed up. Returns
+/// the new program and a map from every old index (plus one past the end) to
+/// its new index.
+fn rewrite(
+    program: Vec<Instruction>,
+    mut emit: impl FnMut(usize, Instruction, &mut Vec<Rewritten>),
+) -> (Vec<Instruction>, Vec<usize>) {
+    let mut out: Vec<Rewritten> = Vec::new();
+    let mut map = Vec::with_capacity(program.len() + 1);
+    for (ip, instr) in program.into_iter().enumerate() {
+        map.push(out.len());
+        emit(ip, instr, &mut out);
+    }
+    map.push(out.len());
+
+    // A target before the start of the program is left as it was
+    let relocate = |old: usize, offset: isize, new: usize| match usize::try_from((old as isize) + offset) {
+        Ok(target) => moved(&map, target) as isize - new as isize,
+        Err(_) => offset,
+    };
+    let program = out.into_iter().enumerate().map(|(new, (instr, origin))| {
+        let Some(old) = origin else { return instr };
+        match instr {
+            Instruction::Jump(offset) => Instruction::Jump(relocate(old, offset, new)),
+            Instruction::IfZero(offset) => Instruction::IfZero(relocate(old, offset, new)),
+            Instruction::RelCall(offset) => Instruction::RelCall(relocate(old, offset, new)),
+            Instruction::Call(addr) => Instruction::Call(moved(&map, addr)),
+            other => other,
+        }
+    }).collect();
+    (program, map)
+}
+
+/// Where the old index `addr` ends up under a rewrite's old-to-new `map`.
+/// Indices past the end of the old program keep their distance from its end.
+fn moved(map: &[usize], addr: usize) -> usize {
+    let end = map.len() - 1;
+    match map.get(addr) {
+        Some(&new) => new,
+        None => map[end].saturating_add(addr - end),
+    }
+}
+
+/// Moves the instruction pointer, return addresses and frame entries of a
+/// run through a rewrite's old-to-new `map`.
+fn remap_run(map: &[usize], ip: &mut usize, return_stack: &mut [usize], frames: &mut [(usize, usize)]) {
+    *ip = moved(map, *ip);
+    for addr in return_stack.iter_mut() {
+        *addr = moved(map, *addr);
+    }
+    for (entry, _) in frames.iter_mut() {
+        *entry = moved(map, *entry);
+    }
+}
+
+/// Every index that control can transfer to other than by falling through:
+/// branch targets, call targets and word entry points.
+fn jump_targets(program: &[Instruction], dictionary: &HashMap<String, usize>) -> HashSet<usize> {
+    let mut targets: HashSet<usize> = dictionary.values().copied().collect();
+    for (ip, instr) in program.iter().enumerate() {
+        match instr {
+            Instruction::Jump(offset) | Instruction::IfZero(offset) | Instruction::RelCall(offset) => {
+                targets.insert(((ip as isize) + offset) as usize);
+            }
+            Instruction::Call(addr) => {
+                targets.insert(*addr);
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// Merges runs of consecutive `Push` instructions into a single `PushN`.
+/// A run is split at any jump target so branches still land on a boundary.
+/// Returns the new program and the old-to-new index map.
+fn coalesce_pushes(program: Vec<Instruction>, dictionary: &HashMap<String, usize>) -> (Vec<Instruction>, Vec<usize>) {
+    let targets = jump_targets(&program, dictionary);
+    let mut previous_push = false;
+    let (program, map) = rewrite(program, |ip, instr, out| {
+        let Instruction::Push(value) = instr else {
+            previous_push = false;
+            out.push((instr, Some(ip)));
+            return;
+        };
+        let merge = previous_push && !targets.contains(&ip);
+        previous_push = true;
+        match out.last_mut() {
+            Some((Instruction::PushN(values), _)) if merge => values.push(value),
+            Some((last @ Instruction::Push(_), _)) if merge => {
+                let Instruction::Push(first) = *last else { unreachable!() };
+                *last = Instruction::PushN(vec![first, value]);
+            }
+            _ => out.push((Instruction::Push(value), Some(ip))),
+        }
+    });
+    (program, map)
+}
+
+/// Page written by `VM::to_html`. `{{listing}}` becomes the escaped
+/// disassembly, `{{snapshots}}` a JSON array of `{"ip":..,"stack":[..]}`
+/// and `{{error}}` a JSON string or `null`.
+const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>tiny_forth</title>
+<style>
+body { font-family: monospace; display: flex; gap: 3em; }
+.current { background: #ffe08a; }
+#stack div { border: 1px solid #888; padding: 0.2em 1em; margin: 2px 0; min-width: 4em; }
+#error { color: #b00; }
+</style>
+</head>
+<body>
+<pre id="listing">{{listing}}</pre>
+<div>
+<button id="back">&larr;</button> <span id="position"></span> <button id="forward">&rarr;</button>
+<p id="ip"></p>
+<div id="stack"></div>
+<p id="error"></p>
+</div>
+<script>
+const snapshots = {{snapshots}};
+const error = {{error}};
+let step = 0;
+function show() {
+  const snapshot = snapshots[step];
+  document.querySelectorAll('.current').forEach(line => line.classList.remove('current'));
+  const line = document.getElementById('ip-' + snapshot.ip);
+  if (line) line.classList.add('current');
+  document.getElementById('position').textContent = 'step ' + step + ' of ' + (snapshots.length - 1);
+  document.getElementById('ip').textContent = 'ip ' + snapshot.ip;
+  document.getElementById('stack').replaceChildren(...snapshot.stack.slice().reverse().map(value => {
+    const cell = document.createElement('div');
+    cell.textContent = value;
+    return cell;
+  }));
+  document.getElementById('error').textContent = step === snapshots.length - 1 && error ? error : '';
+}
+document.getElementById('back').onclick = () => { if (step > 0) { step -= 1; show(); } };
+document.getElementById('forward').onclick = () => { if (step < snapshots.length - 1) { step += 1; show(); } };
+show();
+</script>
+</body>
+</html>
+"#;
+
+/// Escapes `text` for use in HTML element content or attributes.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// `text` as a JSON string literal that is also safe inside a `<script>`.
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '<' => out.push_str("\\u003c"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
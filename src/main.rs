@@ -1,271 +1,96 @@
-use std::collections::HashMap;
-
-#[derive(Debug)]
-enum Instruction {
-    Push(i32),
-    Add,
-    Mul,
-    Dup,
-    Drop,
-    Swap,
-    Over,
-    Rot,
-    Nip,
-    Tuck,
-    TwoDup,
-    TwoDrop,
-    TwoSwap,
-    Depth,
-    Jump(isize),
-    IfZero(isize),
-    Call(usize),
-    CallWord(String),
-    Return,
-    Halt,
-}
-
-#[derive(Debug)]
-struct VM {
-    stack: Vec<i32>,
-    program: Vec<Instruction>,
-    ip: usize,
-    return_stack: Vec<usize>,
-    dictionary: HashMap<String, usize>,
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process::ExitCode;
+
+use tiny_forth::{disassemble, format_source, Parser, Repl, VM};
+
+const USAGE: &str = "usage: tiny_forth [repl [--echo] | run <file> [--dump] | disasm <file> | fmt <file>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        [] | ["repl"] => repl(Repl::new()),
+        ["repl", "--echo"] => repl(Repl::new().with_echo_results()),
+        ["run", path] => run(path, false),
+        ["run", path, "--dump"] | ["run", "--dump", path] => run(path, true),
+        ["disasm", path] => disasm(path),
+        ["fmt", path] => fmt(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
 }
 
-impl VM {
-    fn new(program: Vec<Instruction>) -> Self {
-        Self {
-            stack: Vec::new(),
-            program,
-            ip: 0,
-            return_stack: Vec::new(),
-            dictionary: HashMap::new(),
+/// Parses the file at `path`, reporting any error against the path.
+fn load(path: &str) -> Option<Parser> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return None;
         }
-    }
+    };
 
-    fn add_word(&mut self, name: &str, address: usize) {
-        self.dictionary.insert(name.to_string(), address);
+    let mut parser = Parser::new();
+    if let Err(err) = parser.parse_reader(BufReader::new(file)) {
+        eprintln!("{}: {}", path, err);
+        return None;
     }
-
-    fn run(&mut self) {
-        while self.ip < self.program.len() {
-            match &self.program[self.ip] {
-                Instruction::Push(value) => {
-                    self.stack.push(*value);
-                }
-                Instruction::Add => {
-                    let b = self.stack.pop().expect("Stack underflow on ADD");
-                    let a = self.stack.pop().expect("Stack underflow on ADD");
-                    self.stack.push(a + b);
-                }
-                Instruction::Mul => {
-                    let b = self.stack.pop().expect("Stack underflow on MUL");
-                    let a = self.stack.pop().expect("Stack underflow on MUL");
-                    self.stack.push(a * b);
-                }
-                Instruction::Dup => {
-                    let top = *self.stack.last().expect("Stack underflow on DUP");
-                    self.stack.push(top);
-                }
-                Instruction::Drop => {
-                    self.stack.pop().expect("Stack underflow on DROP");
-                }
-                Instruction::Swap => {
-                    let b = self.stack.pop().expect("Stack underflow on SWAP");
-                    let a = self.stack.pop().expect("Stack underflow on SWAP");
-                    self.stack.push(b);
-                    self.stack.push(a);
-                }
-                Instruction::Over => {
-                    if self.stack.len() < 2 {
-                        panic!("Stack underflow on OVER");
-                    }
-                    let val = self.stack[self.stack.len() - 2];
-                    self.stack.push(val);
-                }
-                Instruction::Rot => {
-                    if self.stack.len() < 3 {
-                        panic!("Stack underflow on ROT");
-                    }
-                    let c = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(b);
-                    self.stack.push(c);
-                    self.stack.push(a);
-                }
-                Instruction::Nip => {
-                    if self.stack.len() < 2 {
-                        panic!("Stack underflow on NIP");
-                    }
-                    let top = self.stack.pop().unwrap();
-                    self.stack.pop(); // discard second
-                    self.stack.push(top);
-                }
-                Instruction::Tuck => {
-                    if self.stack.len() < 2 {
-                        panic!("Stack underflow on TUCK");
-                    }
-                    let top = *self.stack.last().unwrap();
-                    let second = self.stack[self.stack.len() - 2];
-                    self.stack.insert(self.stack.len() - 2, top);
-                }
-                Instruction::TwoDup => {
-                    if self.stack.len() < 2 {
-                        panic!("Stack underflow on 2DUP");
-                    }
-                    let len = self.stack.len();
-                    self.stack.push(self.stack[len - 2]);
-                    self.stack.push(self.stack[len - 1]);
-                }
-                Instruction::TwoDrop => {
-                    if self.stack.len() < 2 {
-                        panic!("Stack underflow on 2DROP");
-                    }
-                    self.stack.pop();
-                    self.stack.pop();
-                }
-                Instruction::TwoSwap => {
-                    if self.stack.len() < 4 {
-                        panic!("Stack underflow on 2SWAP");
-                    }
-                    let d = self.stack.pop().unwrap();
-                    let c = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(c);
-                    self.stack.push(d);
-                    self.stack.push(a);
-                    self.stack.push(b);
-                }
-                Instruction::Depth => {
-                    let depth = self.stack.len() as i32;
-                    self.stack.push(depth);
-                }
-                Instruction::Call(addr) => {
-                    self.return_stack.push(self.ip + 1);
-                    self.ip = *addr;
-                    continue;
-                }
-                Instruction::CallWord(name) => {
-                    let addr = self.dictionary.get(name)
-                        .expect(&format!("Unknown word: {}", name));
-                    self.return_stack.push(self.ip + 1);
-                    self.ip = *addr;
-                    continue;
-                }
-                Instruction::Return => {
-                    let ret = self.return_stack.pop().expect("Return stack underflow");
-                    self.ip = ret;
-                    continue;
-                }
-                Instruction::IfZero(offset) => {
-                    let cond = self.stack.pop().expect("Stack underflow on IFZERO");
-                    if cond == 0 {
-                        self.ip = ((self.ip as isize) + offset) as usize;
-                        continue; // skip ip += 1
-                    }
-                }
-                Instruction::Jump(offset) => {
-                    self.ip = ((self.ip as isize) + offset) as usize;
-                    continue;
-                }
-                Instruction::Halt => break,
-            }
-
-
-            self.ip += 1;
-        }
+    for warning in parser.take_warnings() {
+        eprintln!("{}: warning: {}", path, warning);
     }
-
+    Some(parser)
 }
 
-struct Parser {
-    main: Vec<Instruction>,
-    definitions: Vec<Instruction>,
-    dictionary: HashMap<String, usize>,
-}
+fn run(path: &str, dump: bool) -> ExitCode {
+    let Some(parser) = load(path) else { return ExitCode::FAILURE };
 
-impl Parser {
-    fn new() -> Self {
-        Self {
-            main: Vec::new(),
-            definitions: Vec::new(),
-            dictionary: HashMap::new(),
-        }
+    let mut vm = VM::from_parser(parser);
+    if let Err(err) = vm.run() {
+        eprintln!("{}: {}", path, err);
+        return ExitCode::FAILURE;
     }
 
-    fn parse(&mut self, input: &str) {
-        let mut tokens = input.split_whitespace().peekable();
-        let mut defining: Option<String> = None;
-        let mut buffer: Vec<Instruction> = Vec::new();
+    println!("Final stack: {:?}", vm.stack());
 
-        while let Some(token) = tokens.next() {
-            match token {
-                ":" => {
-                    let name = tokens.next().expect("Expected word name after ':'");
-                    defining = Some(name.to_string());
-                    buffer.clear();
-                }
-                ";" => {
-                    if let Some(name) = defining.take() {
-                        buffer.push(Instruction::Return);
-                        let addr = self.main.len() + self.definitions.len() + 1; // +1 for future HALT
-                        self.dictionary.insert(name, addr);
-                        self.definitions.extend(buffer.drain(..));
-                    } else {
-                        panic!("Unexpected ';' outside of word definition");
-                    }
-                }
-                word => {
-                    let instr = if let Ok(n) = word.parse::<i32>() {
-                        Instruction::Push(n)
-                    } else {
-                        match word {
-                            "dup" => Instruction::Dup,
-                            "drop" => Instruction::Drop,
-                            "swap" => Instruction::Swap,
-                            "over" => Instruction::Over,
-                            "+" => Instruction::Add,
-                            "*" => Instruction::Mul,
-                            "depth" => Instruction::Depth,
-                            _ => Instruction::CallWord(word.to_string()),
-                        }
-                    };
-
-                    if defining.is_some() {
-                        buffer.push(instr);
-                    } else {
-                        self.main.push(instr);
-                    }
-                }
-            }
-        }
-    }
-
-    fn finalize(self) -> (Vec<Instruction>, HashMap<String, usize>) {
-        let mut instructions = self.main;
-        instructions.push(Instruction::Halt); // ✅ main program ends here
-        instructions.extend(self.definitions);
-        (instructions, self.dictionary)
+    if dump {
+        print!("{}", vm.dump_state());
     }
+    ExitCode::SUCCESS
 }
 
+fn disasm(path: &str) -> ExitCode {
+    let Some(parser) = load(path) else { return ExitCode::FAILURE };
 
-fn main() {
-    let mut parser = Parser::new();
-    parser.parse("5 square : square dup * ;");
-
-    let (instructions, dictionary) = parser.finalize();
+    let (program, dictionary) = parser.finalize();
+    print!("{}", disassemble(&program, &dictionary));
+    ExitCode::SUCCESS
+}
 
-    for instr in &instructions {
-        println!("{:?}", instr);
+/// Prints the file at `path` laid out by `format_source`.
+fn fmt(path: &str) -> ExitCode {
+    match std::fs::read_to_string(path) {
+        Ok(source) => {
+            print!("{}", format_source(&source));
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            ExitCode::FAILURE
+        }
     }
+}
 
-    let mut vm = VM::new(instructions);
-    vm.dictionary = dictionary;
-    vm.run();
-
-    println!("Final stack: {:?}", vm.stack); // Should be [25]
+/// Runs an interactive session on stdin until EOF or `.quit`.
+fn repl(mut repl: Repl) -> ExitCode {
+    match repl.run(io::stdin().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
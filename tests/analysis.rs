@@ -0,0 +1,122 @@
+use tiny_forth::{disassemble, Instruction, Parser, VM};
+
+fn vm(source: &str) -> VM {
+    let mut parser = Parser::new();
+    parser.parse(source).unwrap();
+    VM::from_parser(parser)
+}
+
+#[test]
+fn fold_constants_turns_constant_calls_into_literals() {
+    let source = ": ten 10 ; : f ten 1 + ; f";
+    let mut plain = vm(source);
+    plain.run().unwrap();
+
+    let mut folded = vm(source);
+    // Linking leaves the call in place, so analyses still see it
+    let f = folded.dictionary()["f"];
+    assert!(matches!(&folded.program()[f], Instruction::CallWord(name) if name == "ten"));
+    folded.fold_constants();
+    assert!(matches!(folded.program()[f], Instruction::Push(10)));
+    folded.run().unwrap();
+    assert_eq!(folded.stack(), plain.stack());
+    assert_eq!(folded.stack(), &[11]);
+}
+
+#[test]
+fn unused_words_lists_only_uncalled_definitions() {
+    let vm = vm(": sq dup * ; : quad sq sq ; : spare 1 ; : ten 10 ; variable x 3 quad ten x !");
+    assert_eq!(vm.unused_words(), ["spare"]);
+}
+
+#[test]
+fn definition_history_keeps_every_redefinition() {
+    let vm = vm(": foo 1 ; : foo 2 ; : foo 3 ;");
+    let history = vm.definition_history("foo");
+    assert_eq!(history.len(), 3);
+    assert!(history.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(history.last(), vm.dictionary().get("foo"));
+    assert!(vm.definition_history("bar").is_empty());
+}
+
+#[test]
+fn small_leaf_words_are_inlined_at_their_call_sites() {
+    let source = ": sq dup * ; 3 sq 4 sq +";
+    let mut plain = vm(source);
+    plain.run().unwrap();
+
+    let mut inlined = vm(source);
+    inlined.inline_small_words(4);
+    let halt = inlined.program().iter().position(|instr| matches!(instr, Instruction::Halt)).unwrap();
+    let main = &inlined.program()[..halt];
+    assert!(!main.iter().any(|instr| matches!(instr, Instruction::CallWord(_) | Instruction::Call(_))));
+    assert_eq!(main.iter().filter(|instr| matches!(instr, Instruction::Mul)).count(), 2);
+    inlined.run().unwrap();
+    assert_eq!(inlined.stack(), plain.stack());
+    assert_eq!(inlined.stack(), &[25]);
+}
+
+#[test]
+fn inlining_mid_run_carries_on_where_it_left_off() {
+    let mut vm = vm(": sq dup * ; : f sq sq ; 3 f 2 sq");
+    // Stop inside the first `sq` called from `f`
+    for _ in 0..3 {
+        vm.step().unwrap();
+    }
+    vm.inline_small_words(4);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[81, 4]);
+}
+
+#[test]
+fn passes_tolerate_addresses_past_the_end_of_the_program() {
+    let mut vm = VM::new(vec![
+        Instruction::Jump(10),
+        Instruction::Call(40),
+        Instruction::IfZero(-5),
+        Instruction::CallWord("sq".to_string()),
+        Instruction::Halt,
+    ]);
+    vm.add_word("ghost", 50);
+    vm.add_word("sq", 30);
+    vm.inline_small_words(8);
+    vm.fold_constants();
+    assert_eq!(vm.program().len(), 5);
+    assert!(matches!(vm.program()[0], Instruction::Jump(10)));
+    assert_eq!(vm.dictionary()["ghost"], 50);
+    vm.run().unwrap();
+}
+
+#[test]
+fn coverage_reports_words_that_were_never_called() {
+    let mut vm = vm(": used 1 ; : unused 2 ; : ten 10 ; used ten").with_coverage();
+    vm.run().unwrap();
+    let (covered, uncovered) = vm.coverage();
+    assert_eq!(covered, ["ten", "used"]);
+    assert_eq!(uncovered, ["unused"]);
+}
+
+#[test]
+fn code_after_exit_is_unreachable() {
+    let exits = vm(": f 1 exit 2 3 ; f");
+    let f = exits.dictionary()["f"];
+    assert_eq!(exits.unreachable_instructions(), [f + 2, f + 3]);
+    assert!(vm("1 2 : g if 3 then ; g").unreachable_instructions().is_empty());
+}
+
+#[test]
+fn disassemble_names_call_targets_and_resolves_jumps() {
+    let mut parser = Parser::new();
+    parser.parse(": square dup * ; 3 square : pos? 0 > if 1 else 0 then ;").unwrap();
+    let (program, dictionary) = parser.finalize();
+    let listing = disassemble(&program, &dictionary);
+    let square = dictionary["square"];
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[1].trim(), format!("1  CALLWORD square -> {}", square));
+    assert!(matches!(program[square], Instruction::Dup));
+    assert!(listing.contains(&format!("square:\n{:4}  DUP\n", square)), "{}", listing);
+
+    let pos = dictionary["pos?"];
+    assert!(listing.contains(&format!("{:4}  IFZERO +3 -> {}\n", pos + 2, pos + 5)), "{}", listing);
+    assert!(listing.contains(&format!("{:4}  JUMP +2 -> {}\n", pos + 4, pos + 6)), "{}", listing);
+}
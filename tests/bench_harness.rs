@@ -0,0 +1,19 @@
+#[path = "../benches/harness/mod.rs"]
+mod harness;
+
+#[test]
+fn every_benchmark_runs_to_completion() {
+    let results = harness::run_suite(1);
+    assert_eq!(results.len(), harness::SUITE.len());
+    for (result, bench) in results.iter().zip(harness::SUITE) {
+        assert_eq!(result.name, bench.name);
+        assert!(!bench.source.is_empty());
+        assert!(result.steps > 0, "{}", result.name);
+        assert!(result.steps_per_sec() > 0.0, "{}", result.name);
+    }
+
+    let json = harness::to_json(&results);
+    for bench in harness::SUITE {
+        assert!(json.contains(&format!("\"name\": \"{}\"", bench.name)), "{}", json);
+    }
+}
@@ -0,0 +1,45 @@
+use std::process::Command;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/squares.fs");
+
+fn tiny_forth(args: &[&str]) -> (Option<i32>, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_tiny_forth")).args(args).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code(), stdout, stderr)
+}
+
+#[test]
+fn run_prints_the_final_stack() {
+    let (code, stdout, stderr) = tiny_forth(&["run", FIXTURE]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(stdout, "Final stack: [25, 4]\n");
+
+    let (code, stdout, _) = tiny_forth(&["run", FIXTURE, "--dump"]);
+    assert_eq!(code, Some(0));
+    assert!(stdout.starts_with("Final stack: [25, 4]\nStack: [25, 4]\n"), "{}", stdout);
+}
+
+#[test]
+fn fmt_and_disasm_print_the_file() {
+    let (code, stdout, _) = tiny_forth(&["fmt", FIXTURE]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, std::fs::read_to_string(FIXTURE).unwrap());
+
+    let (code, stdout, _) = tiny_forth(&["disasm", FIXTURE]);
+    assert_eq!(code, Some(0));
+    assert!(stdout.contains("square:\n"), "{}", stdout);
+}
+
+#[test]
+fn bad_arguments_print_usage() {
+    for args in [&["disasm", FIXTURE, "--dump"][..], &["repl", "--dump"], &["fmt"], &["nope"]] {
+        let (code, stdout, stderr) = tiny_forth(args);
+        assert_eq!(code, Some(2), "{:?}", args);
+        assert!(stdout.is_empty());
+        assert!(stderr.starts_with("usage: "), "{}", stderr);
+    }
+    let (code, _, stderr) = tiny_forth(&["run", "/no/such/file.fs"]);
+    assert_eq!(code, Some(1));
+    assert!(stderr.starts_with("/no/such/file.fs: "), "{}", stderr);
+}
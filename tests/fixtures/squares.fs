@@ -0,0 +1,5 @@
+\ Squares a few numbers and adds them up
+: square ( n -- n*n ) dup * ;
+: sum-squares ( a b -- a*a+b*b ) square swap square + ;
+3 4 sum-squares
+2 square
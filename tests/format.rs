@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use tiny_forth::{format_source, Parser};
+
+/// The program `source` compiles to, by Debug text, and its dictionary.
+fn compiled(source: &str) -> (String, HashMap<String, usize>) {
+    let mut parser = Parser::new();
+    parser.parse(source).unwrap();
+    let (program, dictionary) = parser.finalize();
+    (format!("{:?}", program), dictionary)
+}
+
+#[test]
+fn messy_definitions_get_a_canonical_layout() {
+    let messy = "\\ helpers
+:   square ( n -- n*n )   dup   * ;  3 square
+: abs ( n -- |n| ) dup 0 <   if 0 swap -
+  then ;
+
+
+: count-down begin dup . 1 - dup 0 = until drop ;   : sign dup 0 < if drop -1 else 0 > if 1 else 0 then then ;
+5 count-down   \\ prints 5 to 1
+( done )
+";
+    let formatted = "\\ helpers
+: square ( n -- n*n ) dup * ;
+3 square
+: abs ( n -- |n| )
+  dup 0 < if
+    0 swap -
+  then ;
+
+: count-down
+  begin
+    dup . 1 - dup 0 =
+  until drop ;
+: sign
+  dup 0 < if
+    drop -1
+  else
+    0 > if
+      1
+    else
+      0
+    then
+  then ;
+5 count-down \\ prints 5 to 1
+( done )
+";
+    assert_eq!(format_source(messy), formatted);
+    assert_eq!(format_source(formatted), formatted);
+    assert_eq!(compiled(formatted), compiled(messy));
+}
+
+#[test]
+fn multi_line_comments_are_kept() {
+    let source = "1 ( spans\n  two lines )   2\n: f ( a -- b ) \\ doc\n  1 + ;\n";
+    let formatted = format_source(source);
+    assert_eq!(formatted, "1 ( spans\ntwo lines ) 2\n: f ( a -- b )\n  \\ doc\n  1 + ;\n");
+    assert_eq!(format_source(&formatted), formatted);
+    assert_eq!(compiled(&formatted), compiled(source));
+
+    // A `\` inside a comment ends the line there, so the `)` on that line
+    // doesn't close it
+    let source = "( a \\ b )\n c ) 1 2\n";
+    let formatted = format_source(source);
+    assert_eq!(formatted, "( a \\ b )\nc ) 1 2\n");
+    assert_eq!(format_source(&formatted), formatted);
+    assert_eq!(compiled(&formatted), compiled(source));
+    assert_eq!(compiled(source).0, compiled("1 2").0);
+
+    let source = ": f ( a\n  b ) if 1 then ;\n";
+    let formatted = format_source(source);
+    assert_eq!(formatted, ": f ( a\nb )\n  if\n    1\n  then ;\n");
+    assert_eq!(format_source(&formatted), formatted);
+    assert_eq!(compiled(&formatted), compiled(source));
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use tiny_forth::{Instruction, ParseError, ParseWarning, Parser, VM, VmError};
+
+fn parser(source: &str) -> Parser {
+    let mut parser = Parser::new();
+    parser.parse(source).unwrap();
+    parser
+}
+
+fn run_program(program: Vec<Instruction>, dictionary: &HashMap<String, usize>) -> Vec<i32> {
+    let mut vm = VM::new(program);
+    for (name, &addr) in dictionary {
+        vm.add_word(name, addr);
+    }
+    vm.run().unwrap();
+    vm.stack().to_vec()
+}
+
+#[test]
+fn relative_calls_run_the_same_and_survive_relocation() {
+    let source = ": sq dup * ; : f dup 0 < if sq then 1 + ; -3 f 4 f";
+    let (absolute, dictionary) = parser(source).finalize();
+    let (relative, _) = parser(source).finalize_relative();
+    assert!(!relative.iter().any(|instr| matches!(instr, Instruction::CallWord(_))));
+    let none = HashMap::new();
+    assert_eq!(run_program(relative.clone(), &none), run_program(absolute, &dictionary));
+
+    // Shift everything along by jumping over some padding at the front
+    let mut shifted = vec![Instruction::Jump(4), Instruction::Halt, Instruction::Halt, Instruction::Halt];
+    shifted.extend(relative);
+    assert_eq!(run_program(shifted, &none), [10, 5]);
+}
+
+#[test]
+fn runs_of_pushes_coalesce_into_one_push_n() {
+    let (program, dictionary) = parser("1 2 3 4 5").finalize();
+    assert_eq!(program.len(), 2);
+    assert!(matches!(&program[0], Instruction::PushN(values) if values == &[1, 2, 3, 4, 5]));
+
+    let uncoalesced = (1..=5).map(Instruction::Push).collect();
+    assert_eq!(run_program(program, &dictionary), run_program(uncoalesced, &dictionary));
+}
+
+#[test]
+fn an_open_definition_is_visible_until_its_semicolon() {
+    let mut parser = parser(": square dup");
+    assert_eq!(parser.defining(), Some("square"));
+    assert!(matches!(parser.pending(), [Instruction::Dup]));
+    parser.parse("* ;").unwrap();
+    assert_eq!(parser.defining(), None);
+    assert!(parser.pending().is_empty());
+}
+
+#[test]
+fn builtin_info_documents_stack_effects() {
+    let swap = tiny_forth::builtin_info("swap").unwrap();
+    assert_eq!(swap.effect, "( a b -- b a )");
+    assert!(tiny_forth::builtin_info("square").is_none());
+}
+
+#[test]
+fn expect_must_appear_once_inside_a_test_block() {
+    assert!(matches!(Parser::new().parse("1 expect"), Err(ParseError::UnexpectedExpect { line: 1 })));
+    assert!(matches!(Parser::new().parse("test t expect 1 expect endtest"), Err(ParseError::UnexpectedExpect { line: 1 })));
+    assert!(matches!(
+        Parser::new().parse("test t\n1 endtest"),
+        Err(ParseError::MissingExpect { name, line: 2 }) if name == "t"
+    ));
+}
+
+#[test]
+fn set_token_respells_a_builtin() {
+    let mut parser = Parser::new();
+    parser.set_token("*", "mul").unwrap();
+    assert!(matches!(parser.set_token("square", "sq"), Err(ParseError::UnknownBuiltin { name }) if name == "square"));
+    parser.parse("6 7 mul").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[42]);
+
+    let mut parser = Parser::new();
+    parser.set_token("*", "mul").unwrap();
+    parser.parse("6 7 *").unwrap();
+    let mut vm = VM::from_parser(parser);
+    assert_eq!(vm.run(), Err(tiny_forth::VmError::UnknownWord("*".to_string())));
+}
+
+#[test]
+fn set_token_refuses_another_builtins_spelling() {
+    let mut parser = Parser::new();
+    assert!(matches!(parser.set_token("*", "+"), Err(ParseError::TokenInUse { token, builtin: "+" }) if token == "+"));
+    // Respelling a built-in as itself, or taking a freed spelling, is fine
+    parser.set_token("*", "*").unwrap();
+    parser.set_token("+", "plus").unwrap();
+    parser.set_token("*", "+").unwrap();
+    parser.parse("2 3 + 4 5 plus").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[6, 9]);
+}
+
+#[test]
+fn tokenize_numbers_lines_and_skips_comments() {
+    let tokens = tiny_forth::tokenize(": sq dup * ; \\ squares a number\n3 sq \\ 9\n\n  .");
+    assert_eq!(tokens, [(":", 1), ("sq", 1), ("dup", 1), ("*", 1), (";", 1), ("3", 2), ("sq", 2), (".", 4)]);
+}
+
+#[test]
+fn parse_reader_matches_parsing_the_whole_string() {
+    let source = ": sq\n  dup * ;\n: f dup 0 < if\n  sq then ;\n-3 f \\ done\n2 sq\n";
+    let (program, dictionary) = parser(source).finalize();
+    let mut from_reader = Parser::new();
+    from_reader.parse_reader(std::io::Cursor::new(source)).unwrap();
+    let (reader_program, reader_dictionary) = from_reader.finalize();
+    assert_eq!(format!("{:?}", reader_program), format!("{:?}", program));
+    assert_eq!(reader_dictionary, dictionary);
+
+    let mut broken = Parser::new();
+    let err = broken.parse_reader(std::io::Cursor::new("1 2\nthen")).unwrap_err();
+    assert!(matches!(err, ParseError::UnmatchedControl { word: "then", line: 2 }));
+}
+
+#[test]
+fn a_colon_inside_an_open_definition_is_rejected() {
+    let err = Parser::new().parse(": a 1\n: b ;").unwrap_err();
+    assert!(matches!(err, ParseError::NestedDefinition { name, line: 2 } if name == "a"));
+}
+
+#[test]
+fn forget_removes_a_word_and_everything_after_it() {
+    let vm = VM::from_parser(parser(": a 1 ; : b 2 ; : c 3 ; forget b"));
+    assert_eq!(vm.dictionary().keys().collect::<Vec<_>>(), ["a"]);
+    assert!(matches!(Parser::new().parse("forget dup"), Err(ParseError::ForgetBuiltin { .. })));
+    assert!(matches!(Parser::new().parse("forget nope"), Err(ParseError::UnknownWord { .. })));
+}
+
+#[test]
+fn forget_hands_back_data_space() {
+    let mut vm = VM::from_parser(parser("variable x variable y 4 allot forget y variable z 9 z ! z"));
+    vm.run().unwrap();
+    assert_eq!(vm.memory(), &[0, 9]);
+    assert_eq!(vm.stack(), &[1]);
+}
+
+#[test]
+fn the_word_limit_evicts_the_least_recently_used_word() {
+    let mut parser = Parser::new().with_word_limit(2);
+    parser.parse(": a 1 ; : b 2 ; : c 3 ;").unwrap();
+    let mut names: Vec<String> = VM::from_parser(parser).dictionary().keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, ["b", "c"]);
+
+    // Calling `a` after `b` is defined makes `b` the least recently used
+    let mut parser = Parser::new().with_word_limit(2);
+    parser.parse(": a 1 ; : b 2 ; a : c a dup + ; c").unwrap();
+    let mut vm = VM::from_parser(parser);
+    let mut names: Vec<String> = vm.dictionary().keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, ["a", "c"]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[1, 2]);
+}
+
+#[test]
+fn words_calling_an_evicted_word_keep_working() {
+    let mut parser = Parser::new().with_word_limit(2);
+    parser.parse(": a 1 ; : b a ; : c 3 ; b").unwrap();
+    let mut vm = VM::from_parser(parser);
+    assert!(!vm.dictionary().contains_key("a"));
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[1]);
+
+    // `b` is bound to the second `a`; forgetting that code unbinds it again
+    let mut parser = Parser::new().with_word_limit(4);
+    parser.parse(": a 1 ; : b a ; : x 0 ; : a 2 ; : d b x ; : c 3 ; forget x b").unwrap();
+    let mut vm = VM::from_parser(parser);
+    assert_eq!(vm.run(), Err(VmError::UnknownWord("a".to_string())));
+}
+
+#[test]
+fn control_structures_compile_and_run() {
+    let mut vm = VM::from_parser(parser(": abs dup 0 < if 0 swap - then ; -5 abs 7 abs"));
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[5, 7]);
+
+    let mut vm = VM::from_parser(parser(": sign dup 0 < if drop -1 else 0 > if 1 else 0 then then ; -3 sign 0 sign 8 sign"));
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[-1, 0, 1]);
+
+    let mut vm = VM::from_parser(parser(": count 0 begin 1 + dup 5 = until ; count"));
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[5]);
+}
+
+#[test]
+fn a_definition_may_leave_control_open_across_parse_calls() {
+    let mut parser = parser(": g dup 0 < if");
+    parser.parse("0 swap - then ;").unwrap();
+    parser.parse("-4 g").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[4]);
+
+    assert!(matches!(Parser::new().parse("1 if 2"), Err(ParseError::UnterminatedControl { word: "if", line: 1 })));
+    assert!(matches!(Parser::new().parse(": h if ;"), Err(ParseError::UnterminatedControl { word: "if", line: 1 })));
+    assert!(matches!(Parser::new().parse("then"), Err(ParseError::UnmatchedControl { word: "then", line: 1 })));
+}
+
+#[test]
+fn a_top_level_test_must_close_within_one_parse() {
+    assert!(matches!(Parser::new().parse("test t 1"), Err(ParseError::UnterminatedTest { name, line: 1 }) if name == "t"));
+    assert!(matches!(Parser::new().parse(": f test t ;"), Err(ParseError::UnterminatedTest { line: 1, .. })));
+
+    let mut parser = parser(": f test t 1");
+    parser.parse("expect 1 endtest ; f").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.test_results().passed, 1);
+}
+
+#[test]
+fn decimal_and_hex_literals() {
+    let parse_one = |source: &str| {
+        let mut parser = Parser::new();
+        parser.parse(source).unwrap();
+        parser.finalize().0.remove(0)
+    };
+    assert!(matches!(parse_one("0xFF"), Instruction::Push(255)));
+    assert!(matches!(parse_one("0Xff"), Instruction::Push(255)));
+    assert!(matches!(parse_one("-0x10"), Instruction::Push(-16)));
+    assert!(matches!(parse_one("-5"), Instruction::Push(-5)));
+    assert!(matches!(parse_one("0x"), Instruction::CallWord(name) if name == "0x"));
+    assert!(matches!(parse_one("0x1G"), Instruction::CallWord(name) if name == "0x1G"));
+    assert!(matches!(parse_one("0x100000000"), Instruction::CallWord(_)));
+}
+
+#[test]
+fn redefining_a_word_uses_the_latest_definition() {
+    let mut parser = parser(": square dup * ; : square dup dup * * ; 2 square");
+    assert!(parser.take_warnings().is_empty());
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[8]);
+}
+
+#[test]
+fn shadowing_a_builtin_warns() {
+    let mut parser = parser(": dup 2 * ;\nvariable swap");
+    assert_eq!(parser.take_warnings(), [
+        ParseWarning::ShadowedBuiltin { name: "dup".to_string(), line: 1 },
+        ParseWarning::ShadowedBuiltin { name: "swap".to_string(), line: 2 },
+    ]);
+    assert!(parser.take_warnings().is_empty());
+    parser.parse("3 dup").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[6]);
+}
+
+#[test]
+fn abort_keeps_a_definition_opened_on_an_earlier_line() {
+    let mut parser = Parser::new();
+    let mut vm = VM::new(Vec::new());
+    parser.parse(": g dup 0 < if").unwrap();
+    assert!(matches!(parser.parse("0 swap - : h"), Err(ParseError::NestedDefinition { .. })));
+    parser.abort();
+    assert_eq!(parser.defining(), Some("g"));
+    parser.parse("0 swap - then ;").unwrap();
+    parser.parse("-4 g 5 g").unwrap();
+    parser.relink(&mut vm);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[4, 5]);
+
+    // Top-level code from the failed line is dropped too
+    parser.parse("1 2").unwrap();
+    assert!(parser.parse("3 then").is_err());
+    parser.abort();
+    parser.relink(&mut vm);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[4, 5, 1, 2]);
+}
+
+#[test]
+fn paren_comments_are_skipped_across_lines_and_parses() {
+    let mut parser = parser("1 ( a comment\nover two lines ) 2 (");
+    parser.parse("still inside ) 3 ( trailing)").unwrap();
+    let mut vm = VM::from_parser(parser);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[1, 2, 3]);
+    assert!(matches!(Parser::new().parse(": ( 1 ;"), Err(ParseError::ReservedWord { .. })));
+}
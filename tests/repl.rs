@@ -0,0 +1,95 @@
+use tiny_forth::Repl;
+
+/// Feeds `input` to a fresh session and returns everything it printed.
+fn session(input: &str) -> String {
+    let mut repl = Repl::new().with_output(Vec::new());
+    repl.run(input.as_bytes()).unwrap();
+    String::from_utf8(repl.into_output()).unwrap()
+}
+
+#[test]
+fn errors_are_reported_and_the_session_carries_on() {
+    assert_eq!(
+        session("drop\n1 2 +\ndrop drop\n.\n"),
+        "> Stack underflow on DROP at 0\n[]\n> [3] ok\n> Stack underflow on DROP at 1\n[3]\n> 3 [] ok\n> \n",
+    );
+}
+
+#[test]
+fn a_failing_line_leaves_the_stack_and_memory_as_they_were() {
+    let mut repl = Repl::new().with_output(Vec::new());
+    repl.run("variable x 5 x ! 1 2\n9 x ! 3 drop drop drop drop\nx @\n".as_bytes()).unwrap();
+    let out = String::from_utf8(repl.into_output()).unwrap();
+    assert_eq!(out, "> [1, 2] ok\n> Stack underflow on DROP at 7\n[1, 2]\n> [1, 2, 5] ok\n> \n");
+}
+
+#[test]
+fn words_persist_between_lines_until_quit() {
+    let out = session(": sq dup * ;\n3 sq\nthen\n4 sq\n.quit\n5 sq\n");
+    assert_eq!(out, "> [] ok\n> [9] ok\n> Unmatched 'then' on line 3\n> [9, 16] ok\n> ");
+}
+
+#[test]
+fn echo_results_prints_what_each_line_leaves() {
+    let mut repl = Repl::new().with_output(Vec::new()).with_echo_results();
+    repl.run("2 3 +\n: sq dup * ;\n10 20\n+ sq\ndrop\n".as_bytes()).unwrap();
+    let out = String::from_utf8(repl.into_output()).unwrap();
+    assert_eq!(out, "> 5\n[5] ok\n> [5] ok\n> 10 20\n[5, 10, 20] ok\n> 900\n[5, 900] ok\n> [5] ok\n> \n");
+}
+
+#[test]
+fn entered_lines_accumulate_in_the_history() {
+    let mut repl = Repl::new().with_output(Vec::new());
+    repl.run(": sq dup * ;\n\n3 sq\n  nope  \n.quit\n".as_bytes()).unwrap();
+    assert_eq!(repl.history(), &[": sq dup * ;", "3 sq", "nope"]);
+
+    assert_eq!(repl.history_previous(), Some("nope"));
+    assert_eq!(repl.history_previous(), Some("3 sq"));
+    assert_eq!(repl.history_previous(), Some(": sq dup * ;"));
+    assert_eq!(repl.history_previous(), Some(": sq dup * ;"));
+    assert_eq!(repl.history_next(), Some("3 sq"));
+    assert_eq!(repl.history_next(), Some("nope"));
+    assert_eq!(repl.history_next(), None);
+    assert_eq!(repl.history_next(), None);
+    assert_eq!(repl.history_previous(), Some("nope"));
+}
+
+#[test]
+fn the_history_keeps_only_the_newest_lines() {
+    let mut repl = Repl::new().with_output(Vec::new()).with_history_limit(2);
+    for line in ["1", "2", "3"] {
+        repl.eval(line).unwrap();
+    }
+    assert_eq!(repl.history(), &["2", "3"]);
+    assert_eq!(Repl::new().history_previous(), None);
+
+    // Lowering the limit later drops the oldest lines too
+    let mut repl = Repl::new().with_output(Vec::new());
+    for line in ["1", "2", "3"] {
+        repl.eval(line).unwrap();
+    }
+    let mut repl = repl.with_history_limit(2);
+    assert_eq!(repl.history(), &["2", "3"]);
+    assert_eq!(repl.history_previous(), Some("3"));
+}
+
+#[test]
+fn a_test_split_across_lines_is_rejected_up_front() {
+    let out = session("test t 1\nexpect 1 endtest\n");
+    assert_eq!(
+        out,
+        "> Test 't' opened on line 1 has no 'endtest'\n> Unexpected 'expect' outside of test on line 2\n> \n",
+    );
+}
+
+#[test]
+fn the_prompt_and_banner_are_configurable() {
+    let mut repl = Repl::new().with_output(Vec::new()).with_prompt("forth> ").with_banner("ok {depth}");
+    repl.run("1 2\ndrop drop drop\n".as_bytes()).unwrap();
+    let out = String::from_utf8(repl.into_output()).unwrap();
+    assert_eq!(out, "forth> [1, 2] ok 2\nforth> Stack underflow on DROP at 2\n[1, 2]\nforth> \n");
+
+    let mut quiet = Repl::new().with_output(Vec::new()).with_banner("");
+    quiet.eval("3").unwrap();
+    assert_eq!(quiet.into_output(), b"[3]\n");
+}
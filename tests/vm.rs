@@ -0,0 +1,504 @@
+use tiny_forth::{Cell, Instruction, ParseError, Parser, StepResult, Tag, TestReport, TestResults, VM, VmError};
+
+fn vm(source: &str) -> VM {
+    let mut parser = Parser::new();
+    parser.parse(source).unwrap();
+    VM::from_parser(parser)
+}
+
+fn run(source: &str) -> Vec<i32> {
+    let mut vm = vm(source);
+    vm.run().unwrap();
+    vm.stack().to_vec()
+}
+
+#[test]
+fn step_executes_one_instruction_at_a_time() {
+    // `2 3` is coalesced into a single PushN
+    let mut vm = vm("2 3 +");
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!(vm.stack(), &[2, 3]);
+    assert_eq!(vm.ip(), 1);
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!(vm.stack(), &[5]);
+    assert_eq!(vm.step(), Ok(StepResult::Halted));
+    assert_eq!(vm.step(), Ok(StepResult::Halted));
+    assert_eq!(vm.stack(), &[5]);
+    assert!(vm.return_stack().is_empty());
+}
+
+#[test]
+fn emit_and_dot_write_to_the_output_sink() {
+    let mut vm = vm("65 emit 1 . -2 .").with_output(Vec::new());
+    vm.run().unwrap();
+    assert!(vm.stack().is_empty());
+    assert_eq!(vm.into_output(), b"A1 -2 ");
+}
+
+#[test]
+fn arithmetic_wraps_on_overflow() {
+    assert_eq!(run("2147483647 1 +"), [i32::MIN]);
+    assert_eq!(run("-2147483648 1 -"), [i32::MAX]);
+    assert_eq!(run("65536 65536 *"), [0]);
+    assert_eq!(run("-2147483648 -1 /"), [i32::MIN]);
+    assert_eq!(run("-2147483648 -1 mod"), [0]);
+}
+
+#[test]
+fn underflow_reports_the_op_and_ip_and_keeps_the_stack() {
+    let mut vm = vm("1 2 + +");
+    assert_eq!(vm.run(), Err(VmError::StackUnderflow { op: "ADD", ip: 2 }));
+    assert_eq!(vm.stack(), &[3]);
+    assert_eq!(vm.ip(), 2);
+}
+
+#[test]
+fn unknown_words_fail_when_called() {
+    let mut vm = vm("1 nope 2");
+    assert_eq!(vm.run(), Err(VmError::UnknownWord("nope".to_string())));
+    assert_eq!(vm.stack(), &[1]);
+}
+
+#[test]
+fn return_without_a_call_underflows_the_return_stack() {
+    let mut vm = VM::new(vec![Instruction::Push(1), Instruction::Return]);
+    assert_eq!(vm.run(), Err(VmError::ReturnStackUnderflow));
+    assert_eq!(vm.stack(), &[1]);
+}
+
+#[test]
+fn subtraction_division_and_mod_take_operands_in_forth_order() {
+    let mut vm = vm("10 3 - .").with_output(Vec::new());
+    vm.run().unwrap();
+    assert_eq!(vm.into_output(), b"7 ");
+    assert_eq!(run("3 10 -"), [-7]);
+    assert_eq!(run("20 4 /"), [5]);
+    assert_eq!(run("-7 2 /"), [-3]);
+    assert_eq!(run("7 3 mod"), [1]);
+    assert_eq!(run("-7 3 mod"), [-1]);
+}
+
+#[test]
+fn dividing_by_zero_is_an_error() {
+    assert_eq!(vm("1 0 /").run(), Err(VmError::DivideByZero { ip: 1 }));
+    assert_eq!(vm("1 0 mod").run(), Err(VmError::DivideByZero { ip: 1 }));
+}
+
+#[test]
+fn iota_pushes_the_integers_below_n() {
+    assert_eq!(run("5 iota"), [0, 1, 2, 3, 4]);
+    assert_eq!(run("7 0 iota"), [7]);
+}
+
+#[test]
+fn failed_iota_leaves_its_count_on_the_stack() {
+    let mut negative = vm("-1 iota");
+    assert_eq!(negative.run(), Err(VmError::NegativeCount { op: "IOTA", count: -1, ip: 1 }));
+    assert_eq!(negative.stack(), &[-1]);
+
+    let mut huge = vm("2147483647 iota");
+    assert_eq!(huge.run(), Err(VmError::StackOverflow { op: "IOTA", ip: 1 }));
+    assert_eq!(huge.stack(), &[i32::MAX]);
+
+    // Exactly filling the stack is fine
+    let mut full = vm("4 iota").with_stack_limit(4);
+    full.run().unwrap();
+    assert_eq!(full.stack(), &[0, 1, 2, 3]);
+}
+
+#[test]
+fn fm_mod_matches_the_ans_floored_table() {
+    assert_eq!(run("10 7 fm/mod"), [3, 1]);
+    assert_eq!(run("-10 7 fm/mod"), [4, -2]);
+    assert_eq!(run("10 -7 fm/mod"), [-4, -2]);
+    assert_eq!(run("-10 -7 fm/mod"), [-3, 1]);
+}
+
+#[test]
+fn sm_rem_matches_the_ans_symmetric_table() {
+    assert_eq!(run("10 7 sm/rem"), [3, 1]);
+    assert_eq!(run("-10 7 sm/rem"), [-3, -1]);
+    assert_eq!(run("10 -7 sm/rem"), [3, -1]);
+    assert_eq!(run("-10 -7 sm/rem"), [-3, 1]);
+}
+
+#[test]
+fn prelude_words_are_available_to_user_code() {
+    let mut vm = VM::new_with_prelude("5 1+ 2* negate true false -2147483648 negate").unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[-12, -1, 0, i32::MIN]);
+
+    // Errors count lines from the start of the user's source
+    assert!(matches!(VM::new_with_prelude("1 then"), Err(ParseError::UnmatchedControl { word: "then", line: 1 })));
+    assert!(matches!(VM::new_with_prelude("1\n2 then"), Err(ParseError::UnmatchedControl { word: "then", line: 2 })));
+}
+
+#[test]
+fn test_blocks_tally_passes_and_failures() {
+    let source = "test adds 2 3 + expect 5 endtest
+        test muls 2 2 * expect 5 endtest
+        5 test drops 1 expect drop drop endtest";
+    let mut vm = vm(source).with_output(Vec::new());
+    vm.run().unwrap();
+    assert_eq!(vm.test_results(), TestResults { passed: 1, failed: 2 });
+    assert!(vm.stack().is_empty());
+    let out = String::from_utf8(vm.into_output()).unwrap();
+    assert!(out.starts_with("test adds: ok\ntest muls: FAILED (expected [5], got [4])\n"), "{}", out);
+    assert!(out.contains("test drops: FAILED"), "{}", out);
+}
+
+#[test]
+fn a_word_over_its_step_budget_is_named_in_the_error() {
+    let words = ": quick 1 ; : busy 1 2 3 4 5 6 drop drop drop drop drop drop ; ";
+    let mut fine = vm(&format!("{}quick quick quick", words)).with_word_step_limit(5);
+    fine.run().unwrap();
+    assert_eq!(fine.stack(), &[1, 1, 1]);
+
+    let mut over = vm(&format!("{}quick busy quick", words)).with_word_step_limit(5);
+    assert_eq!(over.run(), Err(VmError::StepBudgetExceeded { word: "busy".to_string() }));
+    assert_eq!(over.stack()[0], 1);
+}
+
+#[test]
+fn with_capacity_avoids_reallocating_within_the_reserved_depths() {
+    let mut parser = Parser::new();
+    parser.parse(": deep 1 2 3 4 5 6 7 8 ; : f deep deep ; f f").unwrap();
+    let (program, dictionary) = parser.finalize();
+    let mut vm = VM::with_capacity(program, 32, 4);
+    for (name, &addr) in &dictionary {
+        vm.add_word(name, addr);
+    }
+    let stack = vm.stack().as_ptr();
+    let return_stack = vm.return_stack().as_ptr();
+    while vm.step().unwrap() == StepResult::Running {
+        assert_eq!(vm.stack().as_ptr(), stack);
+        assert_eq!(vm.return_stack().as_ptr(), return_stack);
+    }
+    assert_eq!(vm.stack(), run(": deep 1 2 3 4 5 6 7 8 ; : f deep deep ; f f").as_slice());
+    assert_eq!(vm.stack().len(), 32);
+}
+
+#[test]
+fn dump_state_shows_the_stack_memory_and_words() {
+    let mut vm = vm("variable x 2 allot : sq dup * ; 7 x ! 3 sq");
+    vm.run().unwrap();
+    let dump = vm.dump_state();
+    assert!(dump.contains("Stack: [9]\n"), "{}", dump);
+    assert!(dump.contains("Memory: 3 cells [7, 0, 0]\n"), "{}", dump);
+    assert!(dump.contains("  sq @ "), "{}", dump);
+    assert!(dump.contains("  x @ "), "{}", dump);
+}
+
+#[test]
+fn state_reports_interpreting_at_run_time() {
+    assert_eq!(run("state : f state ; f"), [0, 0]);
+
+    // A REPL line that leaves a definition open runs while compiling
+    let mut parser = Parser::new();
+    let mut vm = VM::new(Vec::new());
+    parser.parse("state : g 1").unwrap();
+    assert_eq!(parser.state(), -1);
+    parser.relink(&mut vm);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[-1]);
+    parser.parse("; state g").unwrap();
+    assert_eq!(parser.state(), 0);
+    parser.relink(&mut vm);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[-1, 0, 1]);
+}
+
+#[test]
+fn abs_matches_wrapping_abs() {
+    let mut inputs = vec![i32::MIN, i32::MIN + 1, -65536, -1, 0, 1, 65536, i32::MAX - 1, i32::MAX];
+    inputs.extend((-1000..=1000).step_by(7));
+    for x in inputs {
+        assert_eq!(run(&format!("{} abs", x)), [x.wrapping_abs()], "abs of {}", x);
+    }
+    // i32::MIN has no positive counterpart and stays as it is
+    assert_eq!(run("-2147483648 abs"), [i32::MIN]);
+}
+
+#[test]
+fn comparisons_push_forth_flags() {
+    assert_eq!(run("3 3 ="), [-1]);
+    assert_eq!(run("3 4 ="), [0]);
+    assert_eq!(run("3 4 < 4 3 < 4 3 > 3 4 >"), [-1, 0, -1, 0]);
+    assert_eq!(run("0 not 5 not -1 not"), [-1, 0, 0]);
+    assert_eq!(run("-1 0 and -1 -1 and 0 0 or -1 0 or"), [0, -1, 0, -1]);
+}
+
+#[test]
+fn tuck_copies_the_top_below_the_second() {
+    assert_eq!(run("1 2 tuck"), [2, 1, 2]);
+    assert_eq!(run("9 1 2 tuck"), [9, 2, 1, 2]);
+    assert_eq!(vm("1 tuck").run(), Err(VmError::StackUnderflow { op: "TUCK", ip: 1 }));
+}
+
+#[test]
+fn the_step_limit_stops_runaway_programs_only() {
+    let mut finite = vm(": sq dup * ; 3 sq sq").with_step_limit(1000);
+    finite.run().unwrap();
+    assert_eq!(finite.stack(), &[81]);
+
+    let mut forever = vm(": spin spin ; spin").with_step_limit(100).with_return_stack_limit(1000);
+    assert_eq!(forever.run(), Err(VmError::StepLimitExceeded));
+    assert_eq!(forever.steps(), 101);
+
+    let mut looping = vm("begin 0 until").with_step_limit(50);
+    assert_eq!(looping.run(), Err(VmError::StepLimitExceeded));
+}
+
+#[test]
+fn variables_store_and_fetch_cells() {
+    assert_eq!(run("variable x 5 x ! x @"), [5]);
+    assert_eq!(run("variable x variable y 1 x ! 2 y ! x @ y @"), [1, 2]);
+    // `allot` reserves cells after the variable, reachable by address arithmetic
+    assert_eq!(run("variable xs 2 allot variable y 9 xs 2 + ! 4 y ! xs 2 + @ y @"), [9, 4]);
+}
+
+#[test]
+fn out_of_range_addresses_are_errors() {
+    let mut fetch = vm("7 @");
+    assert_eq!(fetch.run(), Err(VmError::InvalidAddress { addr: 7, ip: 1 }));
+    assert_eq!(fetch.stack(), &[7]);
+
+    let mut store = vm("variable x 5 -1 !");
+    assert_eq!(store.run(), Err(VmError::InvalidAddress { addr: -1, ip: 1 }));
+    assert_eq!(store.stack(), &[5, -1]);
+    assert_eq!(store.memory(), &[0]);
+}
+
+#[test]
+fn poison_mode_rejects_reads_of_unstored_cells() {
+    let mut plain = vm("variable x x @");
+    plain.run().unwrap();
+    assert_eq!(plain.stack(), &[0]);
+
+    let mut poisoned = vm("variable x x @").with_poison();
+    assert_eq!(poisoned.run(), Err(VmError::UninitializedRead { addr: 0, ip: 1 }));
+
+    let mut poisoned = vm("variable x variable y 3 y ! y @ x @").with_poison();
+    assert_eq!(poisoned.run(), Err(VmError::UninitializedRead { addr: 0, ip: 6 }));
+    assert_eq!(poisoned.stack(), &[3, 0]);
+
+    let mut stored = vm("variable x 4 x ! x @").with_poison();
+    stored.run().unwrap();
+    assert_eq!(stored.stack(), &[4]);
+}
+
+#[test]
+fn tagged_cells_only_accept_addresses_for_memory_access() {
+    let mut number = vm("variable x 42 @").with_tagged_cells();
+    assert_eq!(number.run(), Err(VmError::NotAnAddress { value: 42, ip: 1 }));
+    // Untagged, 0 is simply the first cell
+    assert_eq!(run("variable x 0 @"), [0]);
+    let mut zero = vm("variable x 0 @").with_tagged_cells();
+    assert_eq!(zero.run(), Err(VmError::NotAnAddress { value: 0, ip: 1 }));
+    let mut scaled = vm("variable x x 1 * @").with_tagged_cells();
+    assert_eq!(scaled.run(), Err(VmError::NotAnAddress { value: 0, ip: 3 }));
+
+    // Offsets, shuffles and addresses kept in memory stay addresses
+    let mut cells = vm("variable xs 2 allot variable p xs 2 + dup 7 swap ! p ! p @ @ xs").with_tagged_cells();
+    cells.run().unwrap();
+    assert_eq!(cells.stack(), &[7, 0]);
+    assert_eq!(cells.tagged_stack().unwrap(), [Cell { value: 7, tag: Tag::Int }, Cell { value: 0, tag: Tag::Addr }]);
+    assert_eq!(vm("1").tagged_stack(), None);
+}
+
+#[test]
+fn allot_needs_a_literal_count() {
+    assert!(matches!(Parser::new().parse("variable x allot"), Err(ParseError::MissingAllotCount { line: 1 })));
+    assert!(matches!(Parser::new().parse("variable x -1 allot"), Err(ParseError::MissingAllotCount { line: 1 })));
+}
+
+#[test]
+fn runaway_recursion_overflows_the_return_stack() {
+    let mut vm = vm(": forever forever ; forever").with_return_stack_limit(16);
+    assert_eq!(vm.run(), Err(VmError::ReturnStackOverflow { word: "forever".to_string() }));
+    assert_eq!(vm.return_stack().len(), 16);
+}
+
+#[test]
+fn pushing_past_the_stack_limit_overflows() {
+    let mut dup = vm("1 2 3 dup").with_stack_limit(3);
+    assert_eq!(dup.run(), Err(VmError::StackOverflow { op: "DUP", ip: 1 }));
+    assert_eq!(dup.stack(), &[1, 2, 3]);
+
+    let mut literals = vm("1 2 3 4").with_stack_limit(3);
+    assert!(matches!(literals.run(), Err(VmError::StackOverflow { ip: 0, .. })));
+    assert!(literals.stack().is_empty());
+}
+
+#[test]
+fn relink_gives_each_line_a_fresh_step_budget() {
+    let mut parser = Parser::new();
+    let mut vm = VM::new(Vec::new()).with_step_limit(10);
+    for _ in 0..5 {
+        parser.parse("1 drop 2 drop 3 drop").unwrap();
+        parser.relink(&mut vm);
+        vm.run().unwrap();
+        assert_eq!(vm.steps(), 7);
+    }
+}
+
+#[test]
+fn example_annotations_are_run_as_tests() {
+    let source = ": square ( 3 -- 9 ) ( -4 -- 16 ) dup * ;
+        : bad ( 2 -- 5 ) 1 + ;
+        : sum ( a b -- a+b ) + ;
+        : broken ( 1 -- 1 ) drop drop ;
+        7 variable x";
+    let mut vm = vm(source);
+    vm.run().unwrap();
+    let report = vm.run_examples();
+    assert_eq!(report.results, TestResults { passed: 2, failed: 2 });
+    assert_eq!(report.failures.len(), 2);
+    assert_eq!(report.failures[0], "bad ( 2 -- 5 ) on line 2: got [3]");
+    assert!(report.failures[1].starts_with("broken ( 1 -- 1 ) on line 4: Stack underflow on DROP"), "{:?}", report.failures);
+    // The VM is left as the examples found it
+    assert_eq!(vm.stack(), &[7]);
+    assert!(vm.return_stack().is_empty());
+    assert_eq!(vm.run(), Ok(()));
+
+    assert_eq!(self::vm(": five 5 ;").run_examples(), TestReport::default());
+}
+
+#[test]
+fn safe_output_escapes_control_characters() {
+    let source = "7 emit 65 emit 10 emit 0 emit 27 emit 127 emit 155 emit 200 emit";
+    let mut raw = vm(source).with_output(Vec::new());
+    raw.run().unwrap();
+    assert_eq!(raw.into_output(), "\x07A\n\x00\x1b\x7f\u{9b}\u{c8}".as_bytes());
+
+    let mut safe = vm(source).with_output(Vec::new()).with_safe_output();
+    safe.run().unwrap();
+    assert_eq!(String::from_utf8(safe.into_output()).unwrap(), "^GA\n^@^[^?\\x9BÈ");
+}
+
+#[test]
+fn emit_writes_code_points_as_utf8() {
+    let mut wide = vm("233 emit 8364 emit 128512 emit").with_output(Vec::new());
+    wide.run().unwrap();
+    assert_eq!(wide.into_output(), [0xC3, 0xA9, 0xE2, 0x82, 0xAC, 0xF0, 0x9F, 0x98, 0x80]);
+
+    for value in [0xD800, 0x110000, -1] {
+        let mut bad = vm(&format!("{} emit", value));
+        assert_eq!(bad.run(), Err(VmError::InvalidChar { value, ip: 1 }));
+        assert_eq!(bad.stack(), &[value]);
+    }
+}
+
+#[test]
+fn crossing_the_soft_stack_threshold_warns_once() {
+    // Goes past 3 with `4 5 6`, drops back, then goes past it again
+    let source = "1 2 3 drop 4 5 6 drop drop 7 8 . . . . .";
+    let mut leaky = vm(source).with_output(Vec::new()).with_soft_stack_warn(3).with_stack_limit(10);
+    leaky.run().unwrap();
+    let out = String::from_utf8(leaky.into_output()).unwrap();
+    assert_eq!(out, "warning: stack depth 5 is past 3; are values being left behind?\n8 7 4 2 1 ");
+    assert_eq!(out.matches("warning").count(), 1);
+
+    let mut quiet = vm("1 2 3 . . .").with_output(Vec::new()).with_soft_stack_warn(3);
+    quiet.run().unwrap();
+    assert_eq!(quiet.into_output(), b"3 2 1 ");
+}
+
+#[test]
+fn to_html_embeds_one_snapshot_per_step() {
+    // The starting state, then one per instruction before HALT
+    let html = vm("2 3 +").to_html();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert_eq!(html.matches("{\"ip\":").count(), 3);
+    assert!(html.contains(r#"[{"ip":0,"stack":[]},{"ip":1,"stack":[2,3]},{"ip":2,"stack":[5]}]"#), "{}", html);
+    assert!(html.contains("const error = null;"));
+
+    let calls = vm(": sq dup * ; 3 sq");
+    assert_eq!(calls.to_html().matches("{\"ip\":").count(), 6);
+    assert!(calls.stack().is_empty());
+
+    let mut failing = vm(": <bad> drop ; 1 <bad> <bad>");
+    failing.step().unwrap();
+    let html = failing.to_html();
+    assert_eq!(html.matches("{\"ip\":").count(), 5);
+    assert!(html.contains(r#"const error = "Stack underflow on DROP at "#), "{}", html);
+    assert!(html.contains("CALLWORD &lt;bad&gt;"), "{}", html);
+    assert!(!html.contains("<bad>"));
+}
+
+#[test]
+fn step_back_undoes_recorded_steps() {
+    let mut vm = vm("2 3 +").with_undo_limit(10);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert_eq!(vm.stack(), &[5]);
+    assert!(vm.step_back());
+    assert_eq!(vm.stack(), &[2, 3]);
+    assert_eq!(vm.ip(), 1);
+    assert_eq!(vm.steps(), 1);
+    assert!(vm.step_back());
+    assert!(vm.stack().is_empty());
+    assert!(!vm.step_back());
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), &[5]);
+}
+
+#[test]
+fn step_back_is_bounded_and_restores_calls_and_memory() {
+    let mut short = vm("1 drop 2 drop 3").with_undo_limit(2);
+    short.run().unwrap();
+    assert!(short.step_back());
+    assert!(short.step_back());
+    assert!(!short.step_back());
+    assert_eq!(short.stack(), &[2]);
+    assert_eq!(short.ip(), 3);
+
+    let mut stores = vm("variable x : set x ! ; 7 set").with_undo_limit(100);
+    stores.run().unwrap();
+    assert_eq!(stores.memory(), &[7]);
+    // Undo the return from `set`, then its store
+    assert!(stores.step_back());
+    assert!(stores.step_back());
+    assert_eq!(stores.memory(), &[0]);
+    assert_eq!(stores.stack(), &[7, 0]);
+    assert_eq!(stores.return_stack().len(), 1);
+
+    let mut off = vm("1 2");
+    off.step().unwrap();
+    assert!(!off.step_back());
+}
+
+#[test]
+fn to_html_leaves_placeholders_in_the_listing_alone() {
+    let html = vm(": {{snapshots}} 1 ; : {{error}} 2 ; {{snapshots}} {{error}}").to_html();
+    assert!(html.contains("CALLWORD {{snapshots}}"), "{}", html);
+    assert!(html.contains("{{error}}:\n"), "{}", html);
+    assert_eq!(html.matches("const snapshots = [").count(), 1);
+    assert_eq!(html.matches("{\"ip\":").count(), 7);
+    assert!(html.contains("const error = null;"));
+}
+
+#[test]
+fn running_examples_leaves_the_undo_log_alone() {
+    let mut vm = vm(": sq ( 3 -- 9 ) dup * ; 2 3").with_undo_limit(100);
+    vm.run().unwrap();
+    assert_eq!(vm.run_examples().results, TestResults { passed: 1, failed: 0 });
+    assert!(vm.step_back());
+    assert!(vm.stack().is_empty());
+    assert_eq!(vm.ip(), 0);
+    assert!(!vm.step_back());
+}
+
+#[test]
+fn examples_follow_the_definition_they_annotate() {
+    let mut replaced = vm(": sq ( 3 -- 9 ) dup * ; forget sq variable sq");
+    assert_eq!(replaced.run_examples(), TestReport::default());
+
+    let mut redefined = vm(": sq ( 3 -- 9 ) dup * ; : sq dup ;");
+    assert_eq!(redefined.run_examples(), TestReport::default());
+
+    // Forgetting a newer definition brings back the older one and its example
+    let mut restored = vm(": sq ( 3 -- 9 ) dup * ; : sq ( 1 -- 1 1 ) dup ; forget sq");
+    let report = restored.run_examples();
+    assert_eq!(report.results, TestResults { passed: 1, failed: 0 });
+}